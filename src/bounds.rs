@@ -0,0 +1,483 @@
+use crate::cfg::{self, Cfg};
+use crate::isa;
+use crate::parser::{AluOp, Instruction, Parser, RegImmAddr};
+
+// Abstract interpretation of the text section, tracking the range of values each register can
+// hold, to find loads and stores that can reach outside the memory, outside the data section or
+// past the end of the label whose address they started from.
+
+// blocks are joined this many times before their ranges are widened to make loops converge,
+// enough for the short counted loops that fit in the text section
+const WIDEN_AFTER: usize = 64;
+
+// the values a register can hold, with i64::MIN and i64::MAX standing for no bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    lo: i64,
+    hi: i64,
+}
+
+impl Range {
+    const TOP: Range = Range {
+        lo: i64::MIN,
+        hi: i64::MAX,
+    };
+
+    fn exactly(value: i64) -> Self {
+        Range {
+            lo: value,
+            hi: value,
+        }
+    }
+
+    fn bounded(&self) -> bool {
+        self.lo != i64::MIN && self.hi != i64::MAX
+    }
+
+    fn constant(&self) -> Option<i64> {
+        (self.lo == self.hi).then_some(self.lo)
+    }
+
+    // The part of the range that is, or isn't, value, or None if there's none.
+    fn split(&self, value: i64, equal: bool) -> Option<Range> {
+        match equal {
+            true => (self.lo <= value && value <= self.hi).then_some(Range::exactly(value)),
+            false if self.constant() == Some(value) => None,
+            false => Some(Range {
+                lo: if self.lo == value { value + 1 } else { self.lo },
+                hi: if self.hi == value { value - 1 } else { self.hi },
+            }),
+        }
+    }
+
+    fn join(&self, other: &Range) -> Range {
+        Range {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    fn add(&self, other: &Range) -> Range {
+        let bound = |a: i64, b: i64, unbounded: i64| match a == unbounded || b == unbounded {
+            true => unbounded,
+            false => a.saturating_add(b),
+        };
+        Range {
+            lo: bound(self.lo, other.lo, i64::MIN),
+            hi: bound(self.hi, other.hi, i64::MAX),
+        }
+    }
+
+    fn neg(&self) -> Range {
+        let neg = |bound: i64| match bound {
+            i64::MIN => i64::MAX,
+            i64::MAX => i64::MIN,
+            bound => -bound,
+        };
+        Range {
+            lo: neg(self.hi),
+            hi: neg(self.lo),
+        }
+    }
+
+    fn mul(&self, other: &Range) -> Range {
+        if !self.bounded() || !other.bounded() {
+            return Range::TOP;
+        }
+        let products = [
+            self.lo.checked_mul(other.lo),
+            self.lo.checked_mul(other.hi),
+            self.hi.checked_mul(other.lo),
+            self.hi.checked_mul(other.hi),
+        ];
+        match products.into_iter().collect::<Option<Vec<i64>>>() {
+            Some(products) => Range {
+                lo: *products.iter().min().unwrap(),
+                hi: *products.iter().max().unwrap(),
+            },
+            None => Range::TOP,
+        }
+    }
+}
+
+// a register's range, and the label it's an address into when it was computed from one
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Value {
+    range: Range,
+    label: Option<String>,
+}
+
+impl Value {
+    const TOP: Value = Value {
+        range: Range::TOP,
+        label: None,
+    };
+
+    fn int(range: Range) -> Self {
+        Value { range, label: None }
+    }
+
+    fn join(&self, other: &Value) -> Value {
+        Value {
+            range: self.range.join(&other.range),
+            label: match self.label == other.label {
+                true => self.label.clone(),
+                false => None,
+            },
+        }
+    }
+}
+
+type State = Vec<Value>;
+
+struct Analysis<'a> {
+    parser: &'a Parser,
+    base: usize,
+    zero: Option<u8>,
+}
+
+impl Analysis<'_> {
+    fn read(&self, state: &State, reg: u8) -> Value {
+        match Some(reg) == self.zero {
+            true => Value::int(Range::exactly(0)),
+            false => state[reg as usize].clone(),
+        }
+    }
+
+    fn operand(&self, state: &State, operand: &RegImmAddr) -> Value {
+        match operand {
+            RegImmAddr::Register(reg) => self.read(state, *reg),
+            RegImmAddr::Imm(imm) => Value::int(Range::exactly(*imm as i64)),
+            RegImmAddr::Address(_) | RegImmAddr::Unresolved(..) => Value::TOP,
+        }
+    }
+
+    // The 64-bit constant pool entry at addr, as an address into its label if it holds one.
+    fn pool_value(&self, addr: isize) -> Value {
+        let parser = self.parser;
+        let slot = match parser
+            .constant_slots
+            .iter()
+            .find(|slot| slot.addr as isize == addr)
+        {
+            Some(slot) => slot,
+            None => return Value::TOP,
+        };
+        let offset = slot.addr - parser.memory_map.constant_pool.base;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&parser.constant_pool[offset..offset + 8]);
+        Value {
+            range: Range::exactly(i64::from_le_bytes(bytes)),
+            label: slot.label.clone(),
+        }
+    }
+
+    fn alu(&self, op: AluOp, lhs: &Value, rhs: &Value) -> Value {
+        let (a, b) = (lhs.range, rhs.range);
+        let shift = b.constant().filter(|amount| (0..64).contains(amount));
+        match op {
+            // an address plus or minus an offset is still an address into the same label
+            AluOp::Add => Value {
+                range: a.add(&b),
+                label: match (&lhs.label, &rhs.label) {
+                    (Some(label), None) | (None, Some(label)) => Some(label.clone()),
+                    _ => None,
+                },
+            },
+            AluOp::Sub => Value {
+                range: a.add(&b.neg()),
+                label: match &rhs.label {
+                    None => lhs.label.clone(),
+                    Some(_) => None,
+                },
+            },
+            AluOp::Mul => Value::int(a.mul(&b)),
+            AluOp::Lsl => match shift {
+                Some(amount) if amount < 63 => Value::int(a.mul(&Range::exactly(1 << amount))),
+                _ => Value::TOP,
+            },
+            AluOp::Lsr | AluOp::Asr => match shift {
+                Some(amount) if a.lo >= 0 => Value::int(Range {
+                    lo: a.lo >> amount,
+                    hi: match a.hi {
+                        i64::MAX => i64::MAX,
+                        hi => hi >> amount,
+                    },
+                }),
+                _ => Value::TOP,
+            },
+            AluOp::Div => match b.constant() {
+                Some(divisor) if divisor > 0 && a.lo >= 0 => Value::int(Range {
+                    lo: a.lo / divisor,
+                    hi: match a.hi {
+                        i64::MAX => i64::MAX,
+                        hi => hi / divisor,
+                    },
+                }),
+                _ => Value::TOP,
+            },
+            AluOp::Mod => match b.constant() {
+                Some(divisor) if divisor > 0 && a.lo >= 0 => Value::int(Range {
+                    lo: 0,
+                    hi: a.hi.min(divisor - 1),
+                }),
+                _ => Value::TOP,
+            },
+            AluOp::And => match (a.constant(), b.constant()) {
+                (Some(mask), _) | (_, Some(mask)) if mask >= 0 => {
+                    Value::int(Range { lo: 0, hi: mask })
+                }
+                _ => Value::TOP,
+            },
+            AluOp::Orr | AluOp::Eor => Value::TOP,
+        }
+    }
+
+    fn write(&self, state: &mut State, reg: u8, value: Value) {
+        if Some(reg) != self.zero {
+            state[reg as usize] = value;
+        }
+    }
+
+    fn step(&self, state: &mut State, index: usize) {
+        let pc = self.base + index * 4;
+        match &self.parser.instructions[index] {
+            Instruction::Alu(op, rd, rn, src) => {
+                let value = self.alu(*op, &self.read(state, *rn), &self.operand(state, src));
+                self.write(state, *rd, value);
+            }
+            Instruction::Neg(rd, src) => {
+                let value = Value::int(self.operand(state, src).range.neg());
+                self.write(state, *rd, value);
+            }
+            Instruction::Ld(rd, RegImmAddr::Address(offset)) => {
+                let value = self.pool_value(pc as isize + *offset as isize);
+                self.write(state, *rd, value);
+            }
+            Instruction::Ld(rd, src) => {
+                let value = self.operand(state, src);
+                self.write(state, *rd, value);
+            }
+            Instruction::LdMem(size, signed, rd, _, _) => {
+                let bits = *size as u32 * 8;
+                let range = match (bits, signed) {
+                    (64, _) => Range::TOP,
+                    (bits, true) => Range {
+                        lo: -(1 << (bits - 1)),
+                        hi: (1 << (bits - 1)) - 1,
+                    },
+                    (bits, false) => Range {
+                        lo: 0,
+                        hi: (1 << bits) - 1,
+                    },
+                };
+                self.write(state, *rd, Value::int(range));
+            }
+            Instruction::Swap(rd, rn) => {
+                let (a, b) = (self.read(state, *rd), self.read(state, *rn));
+                self.write(state, *rd, b);
+                self.write(state, *rn, a);
+            }
+            instruction => {
+                for reg in isa::written(instruction) {
+                    self.write(state, reg, Value::TOP);
+                }
+            }
+        }
+    }
+
+    // The state after the instruction at index, the last of the block from start, on the way to
+    // next, or None if CBZ/CBNZ can't go that way.
+    fn edge(&self, state: &State, start: usize, index: usize, next: usize) -> Option<State> {
+        let instructions = &self.parser.instructions;
+        let (rn, zero_when_taken) = match &instructions[index] {
+            Instruction::CBZ(rn, _) => (*rn, true),
+            Instruction::CBNZ(rn, _) => (*rn, false),
+            _ => return Some(state.clone()),
+        };
+        let targets = cfg::successors(instructions, self.base, index);
+        // a branch to the next instruction goes there either way
+        if targets.iter().all(|target| *target == next) {
+            return Some(state.clone());
+        }
+        let zero = (next != index + 1) == zero_when_taken;
+        let mut state = state.clone();
+        let mut value = self.read(&state, rn);
+        value.range = value.range.split(0, zero)?;
+        self.write(&mut state, rn, value);
+
+        // a difference like "SUB R4, R3, 10" just before also says whether R3 is 10, which is
+        // what keeps a counter compared that way bounded in its loop
+        let compared = match index > start {
+            true => match &instructions[index - 1] {
+                Instruction::Alu(AluOp::Sub, rd, rm, RegImmAddr::Imm(imm)) => {
+                    Some((*rd, *rm, *imm as i64))
+                }
+                Instruction::Alu(AluOp::Add, rd, rm, RegImmAddr::Imm(imm)) => {
+                    Some((*rd, *rm, -(*imm as i64)))
+                }
+                _ => None,
+            },
+            false => None,
+        };
+        if let Some((_, rm, equal)) =
+            compared.filter(|(rd, rm, _)| *rd == rn && rm != rd && Some(*rm) != self.zero)
+        {
+            let mut value = self.read(&state, rm);
+            value.range = value.range.split(equal, zero)?;
+            self.write(&mut state, rm, value);
+        }
+        Some(state)
+    }
+
+    // Checks a load or store against the memory, the data section and the label its address
+    // came from.
+    fn check_access(&self, state: &State, index: usize) -> Option<String> {
+        let parser = self.parser;
+        let instruction = &parser.instructions[index];
+        let (size, rn, offset, verb) = match instruction {
+            Instruction::LdMem(size, _, _, rn, offset) => (*size, *rn, offset, "read"),
+            Instruction::St(size, _, rn, offset) => (*size, *rn, offset, "write"),
+            _ => return None,
+        };
+        let address = self.alu(
+            AluOp::Add,
+            &self.read(state, rn),
+            &self.operand(state, offset),
+        );
+        // nothing is known about where it goes, as for an index that runs until a 0 is read
+        if !address.range.bounded() {
+            return None;
+        }
+        let (lo, hi) = (address.range.lo, address.range.hi + size as i64 - 1);
+        let fmt = |addr: i64| match addr < 0 {
+            true => format!("-{:#06x}", -(addr as i128)),
+            false => format!("{:#06x}", addr),
+        };
+
+        let map = &parser.memory_map;
+        let mut problems: Vec<String> = Vec::new();
+        // the label can also be a constant, like __stack_top, that has no size
+        if let Some((label, start)) = address
+            .label
+            .as_ref()
+            .and_then(|label| Some((label, *parser.mapping.get(label)? as i64)))
+        {
+            if let Some(size) = parser.label_sizes.get(label).filter(|size| **size > 0) {
+                let end = start + *size as i64;
+                if lo < start {
+                    problems.push(format!("before the start of {}", label));
+                }
+                if hi >= end {
+                    problems.push(format!("past the end of {}", label));
+                }
+                if lo < start || hi >= end {
+                    problems.last_mut().unwrap().push_str(&format!(
+                        " ({} bytes at {})",
+                        size,
+                        fmt(start)
+                    ));
+                }
+            }
+        }
+        if lo < 0 || hi >= map.memory_size() as i64 {
+            problems.push(format!("outside the {}-byte memory", map.memory_size()));
+        } else if lo < map.data.base as i64 || hi >= map.data.end() as i64 {
+            problems.push("outside the data section".to_string());
+        }
+        if problems.is_empty() {
+            return None;
+        }
+        let mnemonic = match isa::current().encoding_for(instruction) {
+            Some(encoding) => encoding.mnemonic.clone(),
+            None => format!("{:?}", instruction),
+        };
+        let range = match lo == hi {
+            true => fmt(lo),
+            false => format!("{}..{}", fmt(lo), fmt(hi)),
+        };
+        Some(format!(
+            "{} can {} {}, {}",
+            mnemonic,
+            verb,
+            range,
+            problems.join(" and ")
+        ))
+    }
+}
+
+// The loads and stores that can go out of bounds, by instruction index, with what's wrong.
+pub fn check(parser: &Parser, graph: &Cfg) -> Vec<(usize, String)> {
+    let isa = isa::current();
+    let analysis = Analysis {
+        parser,
+        base: graph.base,
+        zero: isa.registers.zero,
+    };
+    let mut entry: Vec<Option<State>> = vec![None; graph.blocks.len()];
+    let mut joins = vec![0usize; graph.blocks.len()];
+    let mut work: Vec<usize> = Vec::new();
+    if !graph.blocks.is_empty() {
+        entry[0] = Some(vec![Value::TOP; isa.registers.count as usize]);
+        work.push(0);
+    }
+    while let Some(block) = work.pop() {
+        let mut state = entry[block].clone().unwrap();
+        let last = graph.blocks[block].end - 1;
+        for index in graph.blocks[block].start..=last {
+            analysis.step(&mut state, index);
+        }
+        for next in cfg::flow(&parser.instructions, graph.base, last) {
+            if next == parser.instructions.len() {
+                continue;
+            }
+            let incoming = match analysis.edge(&state, graph.blocks[block].start, last, next) {
+                Some(incoming) => incoming,
+                None => continue,
+            };
+            let next = graph.block_at(next);
+            let merged = match &entry[next] {
+                None => incoming,
+                Some(old) => {
+                    joins[next] += 1;
+                    old.iter()
+                        .zip(&incoming)
+                        .map(|(old, new)| {
+                            let mut value = old.join(new);
+                            if joins[next] > WIDEN_AFTER {
+                                if value.range.lo < old.range.lo {
+                                    value.range.lo = i64::MIN;
+                                }
+                                if value.range.hi > old.range.hi {
+                                    value.range.hi = i64::MAX;
+                                }
+                            }
+                            value
+                        })
+                        .collect()
+                }
+            };
+            if entry[next].as_ref() != Some(&merged) {
+                entry[next] = Some(merged);
+                if !work.contains(&next) {
+                    work.push(next);
+                }
+            }
+        }
+    }
+
+    let mut problems: Vec<(usize, String)> = Vec::new();
+    for (block, state) in entry.iter().enumerate() {
+        let mut state = match state {
+            Some(state) => state.clone(),
+            None => continue,
+        };
+        for index in graph.blocks[block].start..graph.blocks[block].end {
+            if let Some(problem) = analysis.check_access(&state, index) {
+                problems.push((index, problem));
+            }
+            analysis.step(&mut state, index);
+        }
+    }
+    problems
+}
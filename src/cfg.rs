@@ -0,0 +1,305 @@
+use crate::disasm::format_instruction;
+use crate::parser::{Instruction, Parser, RegImmAddr};
+use std::collections::HashMap;
+
+// The instructions that can run after the one at index, by index. An index one past the last
+// instruction means running off the end of the text section. Branches out of the text section
+// lead nowhere.
+pub fn successors(instructions: &[Instruction], base: usize, index: usize) -> Vec<usize> {
+    let pc = base + index * 4;
+    let target = |offset: &i16| -> Vec<usize> {
+        let addr = pc as isize + *offset as isize;
+        let target = (addr - base as isize) / 4;
+        match addr % 4 == 0 && (0..instructions.len() as isize).contains(&target) {
+            true => vec![target as usize],
+            false => vec![],
+        }
+    };
+    match &instructions[index] {
+        // BR goes back to after a BL, which the BL already leads to
+        Instruction::Halt | Instruction::BR(_) => vec![],
+        Instruction::B(RegImmAddr::Address(offset)) => target(offset),
+        Instruction::BL(RegImmAddr::Address(offset))
+        | Instruction::CBZ(_, RegImmAddr::Address(offset))
+        | Instruction::CBNZ(_, RegImmAddr::Address(offset)) => {
+            let mut next = target(offset);
+            next.push(index + 1);
+            next
+        }
+        _ => vec![index + 1],
+    }
+}
+
+// Like successors, but following calls: a BL goes on to its target and a BR returns to after any
+// BL, carrying what the callee did back to the caller.
+pub fn flow(instructions: &[Instruction], base: usize, index: usize) -> Vec<usize> {
+    let calls = |call: usize| {
+        matches!(instructions[call], Instruction::BL(_))
+            && successors(instructions, base, call).len() == 2
+    };
+    match &instructions[index] {
+        Instruction::BL(_) => match successors(instructions, base, index).as_slice() {
+            // a call that's the last instruction still runs off the end when it returns
+            [target, next] if *next < instructions.len() => vec![*target],
+            next => next.to_vec(),
+        },
+        Instruction::BR(_) => (0..instructions.len())
+            .filter(|call| calls(*call) && call + 1 < instructions.len())
+            .map(|call| call + 1)
+            .collect(),
+        _ => successors(instructions, base, index),
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Halt
+            | Instruction::B(_)
+            | Instruction::BL(_)
+            | Instruction::CBZ(..)
+            | Instruction::CBNZ(..)
+            | Instruction::BR(_)
+    )
+}
+
+// a run of instructions only entered at the top and only left at the bottom
+#[derive(Debug)]
+pub struct Block {
+    pub start: usize, // index of the first instruction
+    pub end: usize,   // one past the last
+    pub successors: Vec<usize>,
+    pub falls_off: bool, // runs past the end of the text section
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    pub base: usize,
+    pub blocks: Vec<Block>,
+    pub reachable: Vec<bool>, // from the first instruction
+    pub exits: Vec<bool>,     // some path from the block stops or leaves the program
+}
+
+impl Cfg {
+    pub fn new(instructions: &[Instruction], base: usize) -> Self {
+        let mut leaders = vec![false; instructions.len() + 1];
+        leaders[0] = true;
+        for index in 0..instructions.len() {
+            if ends_block(&instructions[index]) {
+                leaders[index + 1] = true;
+                for next in successors(instructions, base, index) {
+                    leaders[next] = true;
+                }
+            }
+        }
+        let starts: Vec<usize> = (0..instructions.len())
+            .filter(|index| leaders[*index])
+            .collect();
+        let block_of: HashMap<usize, usize> = starts
+            .iter()
+            .enumerate()
+            .map(|(block, start)| (*start, block))
+            .collect();
+
+        let mut blocks: Vec<Block> = Vec::new();
+        for (block, start) in starts.iter().enumerate() {
+            let end = starts.get(block + 1).copied().unwrap_or(instructions.len());
+            let mut block = Block {
+                start: *start,
+                end,
+                successors: Vec::new(),
+                falls_off: false,
+            };
+            for next in successors(instructions, base, end - 1) {
+                match block_of.get(&next) {
+                    Some(next) if !block.successors.contains(next) => block.successors.push(*next),
+                    Some(_) => (),
+                    None => block.falls_off = true,
+                }
+            }
+            blocks.push(block);
+        }
+
+        let mut reachable = vec![false; blocks.len()];
+        let mut work: Vec<usize> = Vec::new();
+        if !blocks.is_empty() {
+            reachable[0] = true;
+            work.push(0);
+        }
+        while let Some(block) = work.pop() {
+            for next in &blocks[block].successors {
+                if !reachable[*next] {
+                    reachable[*next] = true;
+                    work.push(*next);
+                }
+            }
+        }
+
+        // HALT, BR, running off the end and branching out of the text section all leave
+        let mut exits: Vec<bool> = blocks
+            .iter()
+            .map(|block| block.falls_off || block.successors.is_empty())
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in 0..blocks.len() {
+                if !exits[block] && blocks[block].successors.iter().any(|next| exits[*next]) {
+                    exits[block] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        Self {
+            base,
+            blocks,
+            reachable,
+            exits,
+        }
+    }
+
+    // The block the instruction at index is in.
+    pub fn block_at(&self, index: usize) -> usize {
+        self.blocks.partition_point(|block| block.start <= index) - 1
+    }
+
+    // The reachable loops that can never stop: cycles of blocks that nothing leaves, each by its
+    // header, the first of its blocks entered from outside it or from the start of the program.
+    pub fn infinite_loops(&self) -> Vec<usize> {
+        // the blocks each block gets to by following one or more edges
+        let reaches: Vec<Vec<bool>> = (0..self.blocks.len())
+            .map(|from| {
+                let mut seen = vec![false; self.blocks.len()];
+                let mut work = self.blocks[from].successors.clone();
+                while let Some(block) = work.pop() {
+                    if !seen[block] {
+                        seen[block] = true;
+                        work.extend(&self.blocks[block].successors);
+                    }
+                }
+                seen
+            })
+            .collect();
+
+        let mut headers = Vec::new();
+        for (block, reached) in reaches.iter().enumerate() {
+            if !self.reachable[block] || self.exits[block] || !reached[block] {
+                continue;
+            }
+            let cycle: Vec<usize> = (0..self.blocks.len())
+                .filter(|other| reached[*other] && reaches[*other][block])
+                .collect();
+            // each cycle once, and one that leads on to another is reported there
+            let left = cycle.iter().any(|member| {
+                self.blocks[*member]
+                    .successors
+                    .iter()
+                    .any(|next| !cycle.contains(next))
+            });
+            if cycle[0] != block || left {
+                continue;
+            }
+            let entered = |member: &usize| {
+                *member == 0
+                    || (0..self.blocks.len()).any(|from| {
+                        self.reachable[from]
+                            && !cycle.contains(&from)
+                            && self.blocks[from].successors.contains(member)
+                    })
+            };
+            headers.push(cycle.iter().copied().find(entered).unwrap_or(block));
+        }
+        headers
+    }
+
+    // Renders the graph in Graphviz DOT, with each block labelled with its instructions and the
+    // source lines they came from. Unreachable blocks are dashed, blocks that can't stop are red.
+    pub fn to_dot(&self, parser: &Parser, source: &str) -> String {
+        let lines: Vec<&str> = source.lines().collect();
+        let mut labels: HashMap<usize, String> = HashMap::new();
+        for (label, addr) in &parser.mapping {
+            let name = labels.entry(*addr).or_insert(label.clone());
+            if label < name {
+                *name = label.clone();
+            }
+        }
+        let pool_base = parser.memory_map.constant_pool.base;
+        let mut pool: HashMap<usize, String> = HashMap::new();
+        for slot in &parser.constant_slots {
+            let offset = slot.addr - pool_base;
+            let mut value = [0u8; 8];
+            value.copy_from_slice(&parser.constant_pool[offset..offset + 8]);
+            let holds = match &slot.label {
+                Some(label) => label.clone(),
+                None => format!("{:#x}", u64::from_le_bytes(value)),
+            };
+            pool.insert(slot.addr, holds);
+        }
+        let name = |addr: usize| -> String {
+            labels
+                .get(&addr)
+                .or_else(|| pool.get(&addr))
+                .cloned()
+                .unwrap_or_else(|| format!("{:#06x}", addr))
+        };
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+
+        let mut dot =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (index, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            if let Some(name) = labels.get(&(self.base + block.start * 4)) {
+                label.push_str(&format!("{}:\\l", escape(name)));
+            }
+            for at in block.start..block.end {
+                let pc = self.base + at * 4;
+                let registers = |reg: u8| parser.register_name(reg, pc);
+                let instruction =
+                    format_instruction(&parser.instructions[at], pc, &name, &registers);
+                let line = match parser.instruction_locs[at] {
+                    Some(loc) => format!(
+                        "  ; {}: {}",
+                        loc.line,
+                        lines
+                            .get(loc.line as usize - 1)
+                            .map(|line| line.trim())
+                            .unwrap_or_default()
+                    ),
+                    None => "  ; .org padding".to_string(),
+                };
+                label.push_str(&format!(
+                    "{:04x}: {:24}{}\\l",
+                    pc,
+                    escape(&instruction),
+                    escape(&line)
+                ));
+            }
+            let mut style = String::new();
+            if !self.reachable[index] {
+                style.push_str(", style=dashed");
+            } else if !self.exits[index] {
+                style.push_str(", color=red");
+            }
+            dot.push_str(&format!("    b{} [label=\"{}\"{}];\n", index, label, style));
+        }
+        for (index, block) in self.blocks.iter().enumerate() {
+            for next in &block.successors {
+                // the fall-through edge is dashed, the branch edge solid
+                let style = match self.blocks[*next].start == block.end {
+                    true => " [style=dashed]",
+                    false => "",
+                };
+                dot.push_str(&format!("    b{} -> b{}{};\n", index, next, style));
+            }
+            if block.falls_off {
+                dot.push_str(&format!("    b{} -> end [style=dashed];\n", index));
+            }
+        }
+        if self.blocks.iter().any(|block| block.falls_off) {
+            dot.push_str("    end [label=\"end of text\", shape=plaintext];\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,288 @@
+use crate::exitcode;
+use log::error;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+// A memory component found in a .circ file, with byte offsets into the file text.
+struct Memory {
+    circuit: String,
+    label: Option<String>,
+    addr_width: usize,
+    data_width: usize,
+    body_start: usize,                // just after the opening <comp ...>
+    body_end: usize,                  // start of </comp>
+    contents: Option<(usize, usize)>, // the <a name="contents"> element
+    attributes: Vec<(String, usize)>, // the other <a> elements and where they start
+}
+
+// Loads the two images into the memories of a Logisim circuit and returns the updated circuit.
+// Each target is either the name of a circuit holding exactly one ROM/RAM, or the label of a
+// ROM/RAM component. Everything other than the contents attributes is left as is.
+pub fn load_images(
+    circ_path: &str,
+    targets: [(&str, &[u8], bool); 2], // (target, image, big endian cells)
+) -> String {
+    let mut circ = match std::fs::read_to_string(circ_path) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Couldn't read circuit \"{}\": {}", circ_path, e);
+            std::process::exit(exitcode::IO);
+        }
+    };
+
+    let mut edits: Vec<(usize, usize, String)> = Vec::new();
+    let memories = find_memories(&circ, circ_path);
+    // the whole lines an element is on, indentation and newline included
+    let line_start =
+        |at: usize| at - (circ[..at].len() - circ[..at].trim_end_matches([' ', '\t']).len());
+    for (target, image, big_endian) in targets {
+        let memory = find_target(&memories, target, circ_path);
+        let contents = contents_attribute(memory, image, big_endian, target);
+        match memory.contents {
+            Some((start, end)) => {
+                let end = match circ[end..].starts_with('\n') {
+                    true => end + 1,
+                    false => end,
+                };
+                edits.push((line_start(start), end, contents))
+            }
+            None => {
+                // logisim keeps attributes sorted by name
+                let body = &circ[memory.body_start..memory.body_end];
+                let at = memory
+                    .attributes
+                    .iter()
+                    .find(|(name, _)| name.as_str() > "contents")
+                    .map(|(_, start)| line_start(*start))
+                    .unwrap_or(
+                        memory.body_start + body.rfind('\n').map(|i| i + 1).unwrap_or(body.len()),
+                    );
+                edits.push((at, at, contents));
+            }
+        }
+    }
+
+    edits.sort_by_key(|(start, _, _)| std::cmp::Reverse(*start));
+    for (start, end, text) in edits {
+        circ.replace_range(start..end, &text);
+    }
+
+    circ
+}
+
+fn find_memories(circ: &str, circ_path: &str) -> Vec<Memory> {
+    let fail = |e: &dyn std::fmt::Display, at: u64| -> ! {
+        error!(
+            "Couldn't parse circuit \"{}\" at byte {}: {}",
+            circ_path, at, e
+        );
+        std::process::exit(exitcode::USAGE);
+    };
+    let attribute = |element: &BytesStart, name: &str| -> Option<String> {
+        match element.try_get_attribute(name) {
+            Ok(Some(attr)) => match attr.unescape_value() {
+                Ok(value) => Some(value.into_owned()),
+                Err(e) => fail(&e, 0),
+            },
+            Ok(None) => None,
+            Err(e) => fail(&e, 0),
+        }
+    };
+
+    // the memory library is usually "4" but follow whatever the file declares
+    let mut lib = "4".to_string();
+    let mut circuit = String::new();
+    // components with the library they're from, and the <a> element being read
+    let mut comps: Vec<(Option<String>, Memory)> = Vec::new();
+    let mut comp: Option<(Option<String>, Memory)> = None;
+    let mut open: Option<(String, usize, String)> = None;
+
+    let mut reader = Reader::from_str(circ);
+    loop {
+        let start = reader.buffer_position() as usize;
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            Err(e) => fail(&e, reader.error_position()),
+        };
+        let end = reader.buffer_position() as usize;
+        match event {
+            Event::Eof => break,
+            Event::Start(e) | Event::Empty(e)
+                if e.name().as_ref() == b"lib"
+                    && attribute(&e, "desc").as_deref() == Some("#Memory") =>
+            {
+                lib = attribute(&e, "name").unwrap_or(lib);
+            }
+            Event::Start(e) if e.name().as_ref() == b"circuit" => {
+                circuit = attribute(&e, "name").unwrap_or_default();
+            }
+            // self-closing <comp .../> elements have no attributes to hold contents
+            Event::Start(e) if e.name().as_ref() == b"comp" => {
+                comp = match attribute(&e, "name").as_deref() {
+                    Some("ROM" | "RAM") => Some((
+                        attribute(&e, "lib"),
+                        Memory {
+                            circuit: circuit.clone(),
+                            label: None,
+                            addr_width: 8,
+                            data_width: 8,
+                            body_start: end,
+                            body_end: end,
+                            contents: None,
+                            attributes: Vec::new(),
+                        },
+                    )),
+                    _ => None,
+                };
+            }
+            Event::End(e) if e.name().as_ref() == b"comp" => {
+                if let Some((lib, mut memory)) = comp.take() {
+                    memory.body_end = start;
+                    comps.push((lib, memory));
+                }
+            }
+            // an attribute's value is either in val or, like contents, the element's text
+            Event::Empty(e) if e.name().as_ref() == b"a" => {
+                if let (Some((_, memory)), Some(name)) = (&mut comp, attribute(&e, "name")) {
+                    let value = attribute(&e, "val").unwrap_or_default();
+                    memory.set(name, value, start, end);
+                }
+            }
+            Event::Start(e) if e.name().as_ref() == b"a" => {
+                open = attribute(&e, "name").map(|name| (name, start, String::new()));
+            }
+            Event::Text(e) => {
+                if let Some((_, _, value)) = &mut open {
+                    match e.unescape() {
+                        Ok(text) => value.push_str(&text),
+                        Err(err) => fail(&err, start as u64),
+                    }
+                }
+            }
+            Event::CData(e) => {
+                if let Some((_, _, value)) = &mut open {
+                    value.push_str(&String::from_utf8_lossy(&e));
+                }
+            }
+            Event::End(e) if e.name().as_ref() == b"a" => {
+                if let (Some((_, memory)), Some((name, at, value))) = (&mut comp, open.take()) {
+                    memory.set(name, value, at, end);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    comps
+        .into_iter()
+        .filter(|(comp_lib, _)| comp_lib.as_ref() == Some(&lib))
+        .map(|(_, memory)| memory)
+        .collect()
+}
+
+impl Memory {
+    // Records the <a> element from start to end.
+    fn set(&mut self, name: String, value: String, start: usize, end: usize) {
+        match name.as_str() {
+            "label" => self.label = Some(value),
+            "addrWidth" => self.addr_width = value.trim().parse().unwrap_or(8),
+            "dataWidth" => self.data_width = value.trim().parse().unwrap_or(8),
+            "contents" => {
+                self.contents = Some((start, end));
+                return;
+            }
+            _ => (),
+        }
+        self.attributes.push((name, start));
+    }
+}
+
+fn find_target<'a>(memories: &'a [Memory], target: &str, circ_path: &str) -> &'a Memory {
+    let by_label: Vec<&Memory> = memories
+        .iter()
+        .filter(|m| m.label.as_deref() == Some(target))
+        .collect();
+    let by_circuit: Vec<&Memory> = memories.iter().filter(|m| m.circuit == target).collect();
+    let candidates = if by_label.is_empty() {
+        by_circuit
+    } else {
+        by_label
+    };
+    match candidates.as_slice() {
+        [memory] => memory,
+        [] => {
+            error!(
+                "No ROM/RAM labelled \"{}\" or inside a circuit named \"{}\" in \"{}\"",
+                target, target, circ_path
+            );
+            std::process::exit(exitcode::USAGE);
+        }
+        _ => {
+            error!(
+                "\"{}\" matches {} memories in \"{}\", use a component label instead",
+                target,
+                candidates.len(),
+                circ_path
+            );
+            std::process::exit(exitcode::USAGE);
+        }
+    }
+}
+
+// The contents attribute as logisim writes it: a header with the address and data widths,
+// then the cells in hex, 8 per line, with trailing zeros dropped and runs as N*value.
+fn contents_attribute(memory: &Memory, image: &[u8], big_endian: bool, target: &str) -> String {
+    let cell_bytes = memory.data_width.div_ceil(8);
+    let mut cells: Vec<u64> = image
+        .chunks(cell_bytes)
+        .map(|chunk| {
+            let mut bytes = chunk.to_vec();
+            bytes.resize(cell_bytes, 0);
+            if !big_endian {
+                bytes.reverse();
+            }
+            bytes
+                .iter()
+                .fold(0u64, |cell, byte| (cell << 8) | *byte as u64)
+        })
+        .collect();
+    if memory.addr_width < usize::BITS as usize && cells.len() > 1 << memory.addr_width {
+        error!(
+            "Image for \"{}\" needs {} cells but the memory only has {}",
+            target,
+            cells.len(),
+            1usize << memory.addr_width
+        );
+        std::process::exit(exitcode::USAGE);
+    }
+    while cells.last() == Some(&0) {
+        cells.pop();
+    }
+
+    let mut entries: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < cells.len() {
+        let run = cells[i..]
+            .iter()
+            .take_while(|cell| **cell == cells[i])
+            .count();
+        if run >= 4 {
+            entries.push(format!("{}*{:x}", run, cells[i]));
+            i += run;
+        } else {
+            entries.push(format!("{:x}", cells[i]));
+            i += 1;
+        }
+    }
+
+    let mut contents = format!(
+        "      <a name=\"contents\">addr/data: {} {}\n",
+        memory.addr_width, memory.data_width
+    );
+    for line in entries.chunks(8) {
+        contents.push_str(&line.join(" "));
+        contents.push('\n');
+    }
+    contents.push_str("</a>\n");
+    contents
+}
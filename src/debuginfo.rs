@@ -0,0 +1,120 @@
+use crate::parser::Parser;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct TextEntry {
+    pub address: usize,
+    pub line: u32,
+    pub col: u32,
+    pub label: Option<String>, // closest label at or before the address
+}
+
+#[derive(Debug, Serialize)]
+pub struct DataEntry {
+    pub address: usize,
+    pub size: usize,
+    pub label: Option<String>,
+    pub directive: String,
+    pub line: u32,
+    pub col: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConstantPoolEntry {
+    pub address: usize,
+    pub size: usize,
+    pub label: Option<String>, // None when the slot holds a plain value
+    pub line: u32,             // the LD that uses the slot
+    pub col: u32,
+}
+
+// Maps addresses in the assembled images back to the source, so that tools can show source
+// positions for PC values and memory addresses without reparsing the .cry file.
+#[derive(Debug, Serialize)]
+pub struct DebugInfo {
+    pub file: String,
+    pub text: Vec<TextEntry>,
+    pub data: Vec<DataEntry>,
+    pub constant_pool: Vec<ConstantPoolEntry>,
+}
+
+impl DebugInfo {
+    pub fn new(file: &str, source: &str, parser: &Parser) -> Self {
+        let map = &parser.memory_map;
+        let lines: Vec<&str> = source.lines().collect();
+
+        let mut labels: Vec<(&String, usize)> = parser
+            .mapping
+            .iter()
+            .map(|(name, addr)| (name, *addr))
+            .collect();
+        labels.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+
+        let mut text = Vec::new();
+        for (i, loc) in parser.instruction_locs.iter().enumerate() {
+            let address = map.text.base + i * 4;
+            if let Some(loc) = loc {
+                let label = labels
+                    .iter()
+                    .rev()
+                    .find(|(_, addr)| map.text.base <= *addr && *addr <= address)
+                    .map(|(name, _)| name.to_string());
+                text.push(TextEntry {
+                    address,
+                    line: loc.line,
+                    col: loc.col,
+                    label,
+                });
+            }
+        }
+
+        let mut data = Vec::new();
+        for span in &parser.data_spans {
+            // a span belongs to the label whose sized range covers it
+            let label = labels
+                .iter()
+                .find(|(name, addr)| {
+                    let size = parser.label_sizes.get(*name).copied().unwrap_or(0);
+                    *addr <= span.addr && span.addr < addr + size
+                })
+                .map(|(name, _)| name.to_string());
+            let directive = lines
+                .get(span.loc.line as usize - 1)
+                .and_then(|line| line.get(span.loc.col as usize - 1..))
+                .and_then(|rest| rest.split_whitespace().next())
+                .unwrap_or("")
+                .to_string();
+            data.push(DataEntry {
+                address: span.addr,
+                size: span.len,
+                label,
+                directive,
+                line: span.loc.line,
+                col: span.loc.col,
+            });
+        }
+
+        let constant_pool = parser
+            .constant_slots
+            .iter()
+            .map(|slot| ConstantPoolEntry {
+                address: slot.addr,
+                size: 8,
+                label: slot.label.clone(),
+                line: slot.loc.line,
+                col: slot.loc.col,
+            })
+            .collect();
+
+        Self {
+            file: file.to_string(),
+            text,
+            data,
+            constant_pool,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
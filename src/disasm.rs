@@ -0,0 +1,196 @@
+use crate::isa;
+use crate::parser::{Instruction, RegImmAddr};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+
+// The inverse of txtfilegen::encode_instruction, driven by the ISA. Returns None for the
+// UNDEFINED opcodes.
+pub fn decode_instruction(word: u32) -> Option<Instruction> {
+    isa::current().decode(word)
+}
+
+pub fn register_name(reg: u8) -> String {
+    isa::current().register_name(reg)
+}
+
+// Renders an instruction at address pc in assembler syntax, following the operand shape of its
+// encoding. PC-relative operands are turned into absolute addresses and handed to name, which
+// returns the label or value to print for them. Registers are printed by registers.
+pub fn format_instruction(
+    instruction: &Instruction,
+    pc: usize,
+    name: &dyn Fn(usize) -> String,
+    registers: &dyn Fn(u8) -> String,
+) -> String {
+    let encoding = match isa::current().encoding_for(instruction) {
+        Some(encoding) => encoding,
+        None => return format!("{:?}", instruction),
+    };
+    let mut values = isa::args(instruction)
+        .into_iter()
+        .map(|operand| match operand {
+            RegImmAddr::Register(reg) => registers(reg),
+            RegImmAddr::Imm(imm) => imm.to_string(),
+            RegImmAddr::Address(offset) => name((pc as isize + offset as isize) as usize),
+            RegImmAddr::Unresolved(label, _, _) => label,
+        });
+    // every word of the shape is a slot, in order
+    let mut text = format!("{} ", encoding.mnemonic);
+    let mut word = false;
+    for c in encoding.operands.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            if !word {
+                text.push_str(&values.next().unwrap_or_default());
+            }
+            word = true;
+        } else {
+            text.push(c);
+            word = false;
+        }
+    }
+    text.trim_end().to_string()
+}
+
+// The parts of a .map.json symbol file the disassembler uses.
+#[derive(Debug, Deserialize)]
+pub struct SymbolFile {
+    symbols: Vec<SymbolEntry>,
+    constant_pool: Vec<PoolEntry>,
+    #[serde(default)] // older symbol files don't have it
+    register_aliases: Vec<AliasEntry>,
+}
+
+impl SymbolFile {
+    // The .req alias in force at pc, otherwise the ISA's name for the register.
+    fn register_name(&self, reg: u8, pc: usize) -> String {
+        let alias = self.register_aliases.iter().find(|alias| {
+            alias.register == reg
+                && alias
+                    .ranges
+                    .iter()
+                    .any(|(start, end)| *start <= pc && pc < *end)
+        });
+        match alias {
+            Some(alias) => alias.name.clone(),
+            None => isa::current().conventional_name(reg),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SymbolEntry {
+    name: String,
+    address: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct AliasEntry {
+    name: String,
+    register: u8,
+    ranges: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PoolEntry {
+    address: usize,
+    label: Option<String>,
+    value: u64,
+}
+
+// Disassembles a text section image loaded at text_base. Branch targets get labels, from the
+// symbol file when there is one and made up otherwise. Constant pool loads show the loaded label
+// or value when the symbol file or the RAM image says what it is, and registers go by their
+// aliases when there is a symbol file.
+pub fn disassemble(
+    rom: &[u8],
+    text_base: usize,
+    ram: Option<&[u8]>,
+    symbols: Option<&SymbolFile>,
+) -> String {
+    // the rest of the ROM is padded with zeros, which would read as a long run of HALTs
+    let mut len = rom.len();
+    while len >= 8 && rom[len - 8..len].iter().all(|byte| *byte == 0) {
+        len -= 4;
+    }
+    let instructions: Vec<(usize, u32, Option<Instruction>)> = rom[..len]
+        .chunks(4)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut bytes = [0u8; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let word = u32::from_le_bytes(bytes);
+            (text_base + i * 4, word, decode_instruction(word))
+        })
+        .collect();
+
+    let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+    let mut pool: HashMap<usize, String> = HashMap::new();
+    if let Some(symbols) = symbols {
+        for symbol in &symbols.symbols {
+            labels.entry(symbol.address).or_insert(symbol.name.clone());
+        }
+        for entry in &symbols.constant_pool {
+            let value = match &entry.label {
+                Some(label) => label.clone(),
+                None => format!("{:#x}", entry.value),
+            };
+            pool.insert(entry.address, value);
+        }
+    }
+    for (pc, _, instruction) in &instructions {
+        if let Some(
+            Instruction::B(RegImmAddr::Address(offset))
+            | Instruction::BL(RegImmAddr::Address(offset))
+            | Instruction::CBZ(_, RegImmAddr::Address(offset))
+            | Instruction::CBNZ(_, RegImmAddr::Address(offset)),
+        ) = instruction
+        {
+            let target = (*pc as isize + *offset as isize) as usize;
+            labels.entry(target).or_insert(format!("L{:04x}", target));
+        }
+    }
+
+    let mut text = String::new();
+    for (pc, word, instruction) in &instructions {
+        if let Some(label) = labels.get(pc) {
+            text.push_str(&format!("{}:\n", label));
+        }
+        let name = |addr: usize| -> String {
+            if let Some(label) = labels.get(&addr) {
+                return label.clone();
+            }
+            if let Some(value) = pool.get(&addr) {
+                return value.clone();
+            }
+            match ram.and_then(|ram| ram.get(addr..addr + 8)) {
+                Some(bytes) => format!("{:#x}", u64::from_le_bytes(bytes.try_into().unwrap())),
+                None => format!("{:#06x}", addr),
+            }
+        };
+        let registers = |reg: u8| -> String {
+            match symbols {
+                Some(symbols) => symbols.register_name(reg, *pc),
+                None => register_name(reg),
+            }
+        };
+        let (source, comment) = match instruction {
+            Some(instruction @ Instruction::Ld(_, RegImmAddr::Address(offset))) => (
+                format_instruction(instruction, *pc, &name, &registers),
+                format!(
+                    ", constant pool {:#06x}",
+                    (*pc as isize + *offset as isize) as usize
+                ),
+            ),
+            Some(instruction) => (
+                format_instruction(instruction, *pc, &name, &registers),
+                String::new(),
+            ),
+            None => (format!(".byte4 {:#010x}", word), ", undefined".to_string()),
+        };
+        text.push_str(&format!(
+            "    {:32} // {:04x}: {:08x}{}\n",
+            source, pc, word, comment
+        ));
+    }
+    text
+}
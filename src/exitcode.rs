@@ -0,0 +1,5 @@
+// Process exit codes, so that scripts and the autograder can tell why a run failed.
+pub const SOURCE: i32 = 1; // the program doesn't lex, parse or assemble
+pub const USAGE: i32 = 2; // bad command line, memory map or circuit target
+pub const IO: i32 = 3; // an input couldn't be read or an output couldn't be written
+pub const RUNTIME: i32 = 4; // the simulated program faulted or ran out of steps
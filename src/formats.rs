@@ -0,0 +1,309 @@
+// Output backends for the assembled memory images. Every image is addressed from 0: the RAM
+// image holds absolute addresses and the ROM image is relative to the start of the text region.
+
+// layout of a Logisim image: how many bits each memory cell holds, the byte order within a
+// cell, how many cells go on each line and whether runs are written as N*value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogisimOptions {
+    pub cell_bits: usize,
+    pub big_endian: bool,
+    pub cells_per_line: usize,
+    pub run_length: bool,
+}
+
+// one byte per cell, 16 cells per line
+impl Default for LogisimOptions {
+    fn default() -> Self {
+        Self {
+            cell_bits: 8,
+            big_endian: false,
+            cells_per_line: 16,
+            run_length: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Logisim(LogisimOptions), // Logisim "v3.0 hex words addressed"
+    Binary,                  // raw bytes
+    IntelHex,                // Intel HEX (I8HEX/I32HEX)
+    SRecord,                 // Motorola S-record
+    ReadMemH,                // Verilog $readmemh
+    ReadMemB,                // Verilog $readmemb
+    CArray,                  // C header with a uint8_t array
+    RustArray,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "logisim" => Some(Self::Logisim(LogisimOptions::default())),
+            "bin" | "binary" => Some(Self::Binary),
+            "ihex" | "intel-hex" => Some(Self::IntelHex),
+            "srec" | "s-record" => Some(Self::SRecord),
+            "readmemh" | "memh" => Some(Self::ReadMemH),
+            "readmemb" | "memb" => Some(Self::ReadMemB),
+            "c" => Some(Self::CArray),
+            "rust" | "rs" => Some(Self::RustArray),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Logisim(_) => "txt",
+            Self::Binary => "bin",
+            Self::IntelHex => "hex",
+            Self::SRecord => "srec",
+            Self::ReadMemH => "memh",
+            Self::ReadMemB => "memb",
+            Self::CArray => "h",
+            Self::RustArray => "rs",
+        }
+    }
+
+    // name is used for the S0 header and array identifiers
+    pub fn render(&self, name: &str, image: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Logisim(options) => logisim(image, options).into_bytes(),
+            Self::Binary => image.to_vec(),
+            Self::IntelHex => intel_hex(image).into_bytes(),
+            Self::SRecord => s_record(name, image).into_bytes(),
+            Self::ReadMemH => readmem(image, |byte| format!("{:02x}", byte)).into_bytes(),
+            Self::ReadMemB => readmem(image, |byte| format!("{:08b}", byte)).into_bytes(),
+            Self::CArray => c_array(name, image).into_bytes(),
+            Self::RustArray => rust_array(name, image).into_bytes(),
+        }
+    }
+}
+
+// Logisim "v3.0 hex words addressed" format. Each line starts with the address of its first
+// cell; with run-length compression a run of 4 or more equal cells is written as N*value.
+fn logisim(image: &[u8], options: &LogisimOptions) -> String {
+    let cell_bytes = options.cell_bits / 8;
+    let cells: Vec<u64> = image
+        .chunks(cell_bytes)
+        .map(|chunk| {
+            let mut bytes = chunk.to_vec();
+            bytes.resize(cell_bytes, 0);
+            if !options.big_endian {
+                bytes.reverse();
+            }
+            bytes
+                .iter()
+                .fold(0u64, |cell, byte| (cell << 8) | *byte as u64)
+        })
+        .collect();
+
+    // group the cells into (count, value) entries
+    let mut entries: Vec<(usize, u64)> = Vec::new();
+    for cell in cells {
+        match entries.last_mut() {
+            Some((count, value)) if options.run_length && *value == cell => *count += 1,
+            _ => entries.push((1, cell)),
+        }
+    }
+    // short runs take no less space written out
+    let entries: Vec<(usize, u64)> = entries
+        .into_iter()
+        .flat_map(|(count, value)| {
+            if count < 4 {
+                vec![(1, value); count]
+            } else {
+                vec![(count, value)]
+            }
+        })
+        .collect();
+
+    let mut file: String = "v3.0 hex words addressed\n".to_string();
+    let mut addr = 0;
+    for line in entries.chunks(options.cells_per_line) {
+        let words: Vec<String> = line
+            .iter()
+            .map(|(count, value)| {
+                let word = format!("{:0width$x}", value, width = cell_bytes * 2);
+                if *count > 1 {
+                    format!("{}*{}", count, word)
+                } else {
+                    word
+                }
+            })
+            .collect();
+        file.push_str(format!("{:04x}: {}\n", addr, words.join(" ")).as_str());
+        addr += line.iter().map(|(count, _)| count).sum::<usize>();
+    }
+    file
+}
+
+// :LLAAAATT<data>CC where CC is the two's complement of the sum of all other bytes.
+fn intel_hex_record(record_type: u8, addr: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&addr.to_be_bytes());
+    bytes.push(record_type);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(checksum.wrapping_neg());
+    let mut record = ":".to_string();
+    for byte in bytes {
+        record.push_str(format!("{:02X}", byte).as_str());
+    }
+    record.push('\n');
+    record
+}
+
+fn intel_hex(image: &[u8]) -> String {
+    let mut file = String::new();
+    let mut upper_addr = 0;
+    for (i, chunk) in image.chunks(16).enumerate() {
+        let addr = i * 16;
+        // extended linear address record once we go past 64K
+        if addr >> 16 != upper_addr {
+            upper_addr = addr >> 16;
+            file.push_str(&intel_hex_record(4, 0, &(upper_addr as u16).to_be_bytes()));
+        }
+        file.push_str(&intel_hex_record(0, addr as u16, chunk));
+    }
+    file.push_str(&intel_hex_record(1, 0, &[]));
+    file
+}
+
+// S<type><count><address><data><checksum> where the checksum is the one's complement of the
+// sum of the count, address and data bytes.
+fn s_record_line(record_type: u8, addr: usize, addr_len: usize, data: &[u8]) -> String {
+    let mut bytes = vec![(addr_len + data.len() + 1) as u8];
+    bytes.extend_from_slice(&(addr as u32).to_be_bytes()[4 - addr_len..]);
+    bytes.extend_from_slice(data);
+    let checksum = !bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(checksum);
+    let mut record = format!("S{}", record_type);
+    for byte in bytes {
+        record.push_str(format!("{:02X}", byte).as_str());
+    }
+    record.push('\n');
+    record
+}
+
+fn s_record(name: &str, image: &[u8]) -> String {
+    // pick the smallest address width that covers the image
+    let (data_type, end_type, addr_len) = if image.len() <= 0x10000 {
+        (1, 9, 2)
+    } else if image.len() <= 0x1000000 {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+    let mut file = s_record_line(0, 0, 2, name.as_bytes());
+    let mut count = 0;
+    for (i, chunk) in image.chunks(16).enumerate() {
+        file.push_str(&s_record_line(data_type, i * 16, addr_len, chunk));
+        count += 1;
+    }
+    if count <= 0xffff {
+        file.push_str(&s_record_line(5, count, 2, &[]));
+    }
+    file.push_str(&s_record_line(end_type, 0, addr_len, &[]));
+    file
+}
+
+// one byte per word, 16 words per line, each line prefixed with an @address marker
+fn readmem(image: &[u8], word: fn(u8) -> String) -> String {
+    let mut file = String::new();
+    for (i, chunk) in image.chunks(16).enumerate() {
+        let words: Vec<String> = chunk.iter().map(|byte| word(*byte)).collect();
+        file.push_str(format!("@{:04x} {}\n", i * 16, words.join(" ")).as_str());
+    }
+    file
+}
+
+fn identifier(name: &str) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    ident
+}
+
+fn array_body(image: &[u8]) -> String {
+    let mut body = String::new();
+    for chunk in image.chunks(16) {
+        let bytes: Vec<String> = chunk
+            .iter()
+            .map(|byte| format!("0x{:02x},", byte))
+            .collect();
+        body.push_str(format!("    {}\n", bytes.join(" ")).as_str());
+    }
+    body
+}
+
+fn c_array(name: &str, image: &[u8]) -> String {
+    let ident = identifier(name);
+    format!(
+        "#ifndef {upper}_H\n#define {upper}_H\n\n#include <stdint.h>\n\n\
+         static const uint8_t {ident}[{len}] = {{\n{body}}};\n\n#endif\n",
+        upper = ident.to_uppercase(),
+        ident = ident,
+        len = image.len(),
+        body = array_body(image),
+    )
+}
+
+fn rust_array(name: &str, image: &[u8]) -> String {
+    format!(
+        "pub const {}: [u8; {}] = [\n{}];\n",
+        identifier(name).to_uppercase(),
+        image.len(),
+        array_body(image),
+    )
+}
+
+// Reads an image back from a file written by one of the backends above. Logisim images are
+// recognised by their header, with the cell width taken from the digits per word; anything
+// else is taken to be raw bytes.
+pub fn read_image(contents: &[u8], big_endian: bool) -> Result<Vec<u8>, String> {
+    let text = match std::str::from_utf8(contents) {
+        Ok(text) if text.starts_with("v3.0 hex") => text,
+        _ => return Ok(contents.to_vec()),
+    };
+    let mut image: Vec<u8> = Vec::new();
+    for (i, line) in text.lines().enumerate().skip(1) {
+        let (addr, words) = match line.split_once(':') {
+            Some((addr, words)) => match usize::from_str_radix(addr.trim(), 16) {
+                Ok(addr) => (addr, words),
+                Err(_) => return Err(format!("line {}: invalid address \"{}\"", i + 1, addr)),
+            },
+            None if line.trim().is_empty() => continue,
+            None => return Err(format!("line {}: expected \"address: words\"", i + 1)),
+        };
+        // the address counts cells, so it can only be placed once the cell width is known
+        let mut addr = Some(addr);
+        for word in words.split_whitespace() {
+            let (count, word) = match word.split_once('*') {
+                Some((count, word)) => match count.parse::<usize>() {
+                    Ok(count) => (count, word),
+                    Err(_) => return Err(format!("line {}: invalid run \"{}\"", i + 1, count)),
+                },
+                None => (1, word),
+            };
+            let value = match u64::from_str_radix(word, 16) {
+                Ok(value) if word.len() % 2 == 0 && word.len() <= 16 => value,
+                _ => return Err(format!("line {}: invalid word \"{}\"", i + 1, word)),
+            };
+            let cell_bytes = word.len() / 2;
+            if let Some(addr) = addr.take() {
+                image.resize(addr * cell_bytes, 0);
+            }
+            let mut bytes = value.to_le_bytes()[..cell_bytes].to_vec();
+            if big_endian {
+                bytes.reverse();
+            }
+            for _ in 0..count {
+                image.extend_from_slice(&bytes);
+            }
+        }
+    }
+    Ok(image)
+}
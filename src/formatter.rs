@@ -0,0 +1,265 @@
+use crate::exitcode;
+use crate::lexer::Lexer;
+use crate::token::{Directive, TokenValue};
+use log::error;
+
+// The least column statements start at, after any labels.
+const INDENT: usize = 4;
+
+// A token of a statement as it's written out.
+struct Word {
+    value: TokenValue,
+    text: String,
+}
+
+// A line of source: the labels it starts with, the statement after them and a comment at its end.
+struct Line {
+    labels: Vec<String>,
+    words: Vec<Word>,
+    comment: Option<String>,
+    indented: bool,
+}
+
+impl Line {
+    fn prefix(&self) -> String {
+        self.labels
+            .iter()
+            .map(|label| format!("{}:", label))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    // .text, .data and .section start at column 0.
+    fn is_section(&self) -> bool {
+        matches!(
+            self.words.first().map(|word| &word.value),
+            Some(TokenValue::SectionDirective(_) | TokenValue::Directive(Directive::Section))
+        )
+    }
+
+    // The mnemonic or directive the operands follow.
+    fn head(&self) -> Option<&Word> {
+        match self.words.first() {
+            Some(
+                word @ Word {
+                    value:
+                        TokenValue::Mnemonic(_)
+                        | TokenValue::DataTypeDirective(_)
+                        | TokenValue::Directive(_),
+                    ..
+                },
+            ) if !self.is_section() => Some(word),
+            _ => None,
+        }
+    }
+}
+
+// fmt: the source in canonical form. Labels start at column 0 and statements at a column past the
+// longest label written before one, with their operands lined up after the longest mnemonic or
+// directive. Mnemonics and registers are in upper case, literals in one style, operands separated
+// by ", ", and comments are kept, those at the end of consecutive lines lined up.
+pub fn format(source: &str, name: &str) -> String {
+    let lexer = Lexer::lossless(source.to_string());
+    let lines = lines(&lexer);
+    let column = lines
+        .iter()
+        .filter(|line| !line.labels.is_empty() && !line.words.is_empty() && !line.is_section())
+        .map(|line| (line.prefix().len() + 1).next_multiple_of(INDENT))
+        .fold(INDENT, usize::max);
+    let width = lines
+        .iter()
+        .filter_map(|line| line.head())
+        .map(|head| head.text.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut rendered: Vec<(String, Option<String>)> = Vec::new();
+    for line in &lines {
+        let code = match (line.is_section(), line.head()) {
+            _ if line.labels.is_empty() && line.words.is_empty() => String::new(),
+            (true, _) => format!("{} {}", line.prefix(), join(&line.words))
+                .trim_start()
+                .to_string(),
+            (false, _) if line.words.is_empty() => line.prefix(),
+            (false, Some(head)) if line.words.len() > 1 => format!(
+                "{:column$}{:width$} {}",
+                line.prefix(),
+                head.text,
+                join(&line.words[1..])
+            ),
+            (false, _) => format!("{:column$}{}", line.prefix(), join(&line.words)),
+        };
+        let code = match (code.is_empty(), &line.comment, line.indented) {
+            (true, Some(_), true) => " ".repeat(column),
+            _ => code,
+        };
+        // collapse runs of blank lines
+        let blank = code.is_empty() && line.comment.is_none();
+        if blank
+            && rendered
+                .last()
+                .is_none_or(|(code, comment)| code.is_empty() && comment.is_none())
+        {
+            continue;
+        }
+        let code = match code.trim().is_empty() {
+            true => code,
+            false => code.trim_end().to_string(),
+        };
+        rendered.push((code, line.comment.clone()));
+    }
+    while rendered
+        .last()
+        .is_some_and(|(code, comment)| code.is_empty() && comment.is_none())
+    {
+        rendered.pop();
+    }
+
+    // comments after code line up within each run of lines that have them
+    let mut text = String::new();
+    let mut start = 0;
+    while start < rendered.len() {
+        let trailing = |(code, comment): &(String, Option<String>)| {
+            !code.trim().is_empty() && comment.is_some()
+        };
+        let end = match trailing(&rendered[start]) {
+            true => (start..rendered.len())
+                .find(|at| !trailing(&rendered[*at]))
+                .unwrap_or(rendered.len()),
+            false => start + 1,
+        };
+        let align = rendered[start..end]
+            .iter()
+            .map(|(code, _)| code.len() + 1)
+            .max()
+            .unwrap_or(0);
+        for (code, comment) in &rendered[start..end] {
+            let line = match comment {
+                Some(comment) if code.trim().is_empty() => format!("{}{}", code, comment),
+                Some(comment) => format!("{:align$}{}", code, comment),
+                None => code.clone(),
+            };
+            text.push_str(&line);
+            text.push('\n');
+        }
+        start = end;
+    }
+
+    // formatting only ever changes whitespace and how tokens are spelled
+    let tokens = |source: &str| -> Vec<TokenValue> {
+        Lexer::lossless(source.to_string())
+            .tokens
+            .into_iter()
+            .map(|token| match token.value {
+                TokenValue::Comment(comment) => TokenValue::Comment(comment.trim_end().to_string()),
+                value => value,
+            })
+            .filter(|value| {
+                !matches!(
+                    value,
+                    TokenValue::Whitespace | TokenValue::Newline | TokenValue::Eof
+                )
+            })
+            .collect()
+    };
+    if tokens(source) != tokens(&text) {
+        error!("Formatting \"{}\" would change what it means", name);
+        std::process::exit(exitcode::SOURCE);
+    }
+    text
+}
+
+fn lines(lexer: &Lexer) -> Vec<Line> {
+    let new_line = || Line {
+        labels: Vec::new(),
+        words: Vec::new(),
+        comment: None,
+        indented: false,
+    };
+    let mut lines = Vec::new();
+    let mut line = new_line();
+    for (index, token) in lexer.tokens.iter().enumerate() {
+        let text = lexer.text(index);
+        let spelled = match &token.value {
+            TokenValue::Whitespace => {
+                line.indented |= line.labels.is_empty() && line.words.is_empty();
+                continue;
+            }
+            TokenValue::Newline | TokenValue::Eof => {
+                // a comment the line ends with is kept apart to line it up
+                if let Some(Word {
+                    value: TokenValue::Comment(_),
+                    ..
+                }) = line.words.last()
+                {
+                    line.comment = line.words.pop().map(|word| word.text);
+                }
+                lines.push(std::mem::replace(&mut line, new_line()));
+                continue;
+            }
+            TokenValue::LabelDef(label) if line.words.is_empty() => {
+                line.labels.push(label.clone());
+                continue;
+            }
+            TokenValue::Mnemonic(mnemonic) => mnemonic.clone(),
+            TokenValue::Register(_) => text.to_uppercase(),
+            TokenValue::Imm(imm) => match text.strip_prefix("0x") {
+                Some(digits) => format!("0x{}", digits.to_lowercase()),
+                None if text.starts_with("0b") => text.to_string(),
+                None => imm.to_string(),
+            },
+            TokenValue::Char(c) => format!("'{}'", escape(&[*c as u8], '\'')),
+            TokenValue::String(bytes) => format!("\"{}\"", escape(bytes, '"')),
+            TokenValue::Comment(comment) => comment.trim_end().to_string(),
+            _ => text.to_string(),
+        };
+        line.words.push(Word {
+            value: token.value.clone(),
+            text: spelled,
+        });
+    }
+    lines
+}
+
+// Words separated by spaces, but not before a comma or ']' or after '['.
+fn join(words: &[Word]) -> String {
+    let mut text = String::new();
+    for (at, word) in words.iter().enumerate() {
+        let spaced = at > 0
+            && !matches!(word.value, TokenValue::Comma | TokenValue::RBracket)
+            && !matches!(words[at - 1].value, TokenValue::LBracket);
+        if spaced {
+            text.push(' ');
+        }
+        text.push_str(&word.text);
+    }
+    text
+}
+
+// The bytes of a character or string literal, with escapes for what isn't printable ASCII or
+// UTF-8.
+fn escape(bytes: &[u8], quote: char) -> String {
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        let mut chars = chunk.valid().chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\n' => text.push_str("\\n"),
+                '\r' => text.push_str("\\r"),
+                '\t' => text.push_str("\\t"),
+                '\\' => text.push_str("\\\\"),
+                c if c == quote => text.push_str(&format!("\\{}", quote)),
+                // an octal digit after \0 would be read as part of it
+                '\0' if !chars.peek().is_some_and(|next| ('0'..='7').contains(next)) => {
+                    text.push_str("\\0")
+                }
+                c if c.is_ascii_control() => text.push_str(&format!("\\x{:02x}", c as u8)),
+                c => text.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    text
+}
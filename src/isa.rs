@@ -0,0 +1,795 @@
+use crate::exitcode;
+use crate::lexer::Lexer;
+use crate::parser::{AluOp, Instruction, RegImmAddr};
+use crate::token::TokenValue;
+use log::error;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::OnceLock;
+
+// The ISA used when --isa isn't given.
+const DEFAULT_ISA: &str = include_str!("../isa.toml");
+
+static ISA: OnceLock<Isa> = OnceLock::new();
+
+// A bit field of the instruction word.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Field {
+    pub lsb: u32,
+    pub width: u32,
+}
+
+impl Field {
+    fn mask(&self) -> u32 {
+        ((1u64 << self.width) - 1) as u32
+    }
+
+    pub fn get(&self, word: u32) -> u32 {
+        (word >> self.lsb) & self.mask()
+    }
+
+    fn put(&self, value: u32) -> u32 {
+        (value & self.mask()) << self.lsb
+    }
+
+    // the value as a two's complement number of the field's width
+    fn get_signed(&self, word: u32) -> i16 {
+        let unused = 32 - self.width;
+        (((self.get(word) << unused) as i32) >> unused) as i16
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registers {
+    pub count: u8,
+    pub prefix: String,
+    pub zero: Option<u8>,
+    pub link: Option<u8>,
+    pub stack: Option<u8>,
+    #[serde(default)]
+    pub aliases: BTreeMap<String, u8>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IsaFile {
+    name: String,
+    #[serde(default)]
+    description: String,
+    fields: HashMap<String, Field>,
+    registers: Registers,
+    instructions: Vec<EntryFile>,
+    #[serde(default)]
+    pseudo: Vec<PseudoFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EntryFile {
+    opcode: String,
+    mnemonic: Option<String>,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    operands: String,
+    operation: Option<String>,
+    size: Option<u8>,
+    #[serde(default)]
+    signed: bool,
+    #[serde(default)]
+    extension: bool,
+    note: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PseudoFile {
+    mnemonic: String,
+    #[serde(default)]
+    operands: String,
+    expansion: Vec<String>,
+    #[serde(default)]
+    extension: bool,
+}
+
+// What an instruction does. The simulator knows how to execute each of these.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Halt,
+    Alu(AluOp),
+    Neg,
+    Mov,
+    LoadConstant,
+    Load(u8, bool), // num bytes, sign extension
+    Store(u8),      // num bytes
+    Swap,
+    B,
+    CBZ,
+    CBNZ,
+    BL,
+    BR,
+}
+
+// The operands an operation takes, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    Register,
+    Source, // a register or an immediate
+    Target,
+    Constant,
+}
+
+impl Operation {
+    fn parse(entry: &EntryFile, name: &str) -> Result<Self, String> {
+        let alu = |op| Ok(Operation::Alu(op));
+        let size = || match entry.size {
+            Some(size @ (1 | 2 | 4 | 8)) => Ok(size),
+            Some(size) => Err(format!("size {} isn't 1, 2, 4 or 8", size)),
+            None => Err(format!("{} needs a size", name)),
+        };
+        match name {
+            "halt" => Ok(Operation::Halt),
+            "add" => alu(AluOp::Add),
+            "sub" => alu(AluOp::Sub),
+            "mul" => alu(AluOp::Mul),
+            "div" => alu(AluOp::Div),
+            "mod" => alu(AluOp::Mod),
+            "asr" => alu(AluOp::Asr),
+            "lsl" => alu(AluOp::Lsl),
+            "lsr" => alu(AluOp::Lsr),
+            "and" => alu(AluOp::And),
+            "orr" => alu(AluOp::Orr),
+            "eor" => alu(AluOp::Eor),
+            "neg" => Ok(Operation::Neg),
+            "mov" => Ok(Operation::Mov),
+            "load_constant" => Ok(Operation::LoadConstant),
+            "load" => Ok(Operation::Load(size()?, entry.signed)),
+            "store" => Ok(Operation::Store(size()?)),
+            "swap" => Ok(Operation::Swap),
+            "b" => Ok(Operation::B),
+            "cbz" => Ok(Operation::CBZ),
+            "cbnz" => Ok(Operation::CBNZ),
+            "bl" => Ok(Operation::BL),
+            "br" => Ok(Operation::BR),
+            _ => Err(format!("unknown operation \"{}\"", name)),
+        }
+    }
+
+    fn args(&self) -> &'static [Arg] {
+        match self {
+            Operation::Halt => &[],
+            Operation::Alu(_) | Operation::Load(..) | Operation::Store(_) => {
+                &[Arg::Register, Arg::Register, Arg::Source]
+            }
+            Operation::Neg => &[Arg::Register, Arg::Source],
+            Operation::Mov | Operation::Swap => &[Arg::Register, Arg::Register],
+            Operation::LoadConstant => &[Arg::Register, Arg::Constant],
+            Operation::B | Operation::BL => &[Arg::Target],
+            Operation::BR => &[Arg::Register],
+            Operation::CBZ | Operation::CBNZ => &[Arg::Register, Arg::Target],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind {
+    Register,
+    Imm,
+    Label, // PC-relative branch target
+    Const, // PC-relative constant pool slot
+}
+
+// A placeholder in an operand shape, and the field its value is encoded in.
+#[derive(Debug)]
+pub struct Slot {
+    pub name: String,
+    pub kind: SlotKind,
+    pub field: Field,
+}
+
+#[derive(Debug)]
+pub enum Shape {
+    Slot(Slot),
+    Memory(Vec<Slot>), // [rn, rm]
+}
+
+// One entry of the instruction list. Entries without an operation are UNDEFINED, and so are
+// extension entries unless extensions are enabled.
+#[derive(Debug)]
+pub struct Encoding {
+    pub opcode: u32,
+    pub mnemonic: String,
+    pub aliases: Vec<String>,
+    pub operands: String, // e.g. "rd, [rn, imm]"
+    pub shape: Vec<Shape>,
+    pub operation: Option<Operation>,
+    pub extension: bool,
+    pub enabled: bool,
+    pub note: Option<String>,
+}
+
+impl Encoding {
+    pub fn slots(&self) -> impl Iterator<Item = &Slot> {
+        self.shape.iter().flat_map(|shape| match shape {
+            Shape::Slot(slot) => std::slice::from_ref(slot),
+            Shape::Memory(slots) => slots.as_slice(),
+        })
+    }
+
+    fn defined(&self) -> bool {
+        self.enabled && self.operation.is_some()
+    }
+
+    fn names(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.mnemonic).chain(&self.aliases)
+    }
+}
+
+// A mnemonic that stands for a sequence of instructions.
+#[derive(Debug)]
+pub struct Pseudo {
+    pub mnemonic: String,
+    pub operands: String,
+    pub shape: Vec<Shape>,
+    pub expansion: Vec<String>,
+    pub extension: bool,
+    pub enabled: bool,
+}
+
+pub struct Isa {
+    pub name: String,
+    pub description: String,
+    pub opcode: Field,
+    pub registers: Registers,
+    pub encodings: Vec<Encoding>,
+    pub pseudos: Vec<Pseudo>,
+}
+
+// Loads the ISA from a file, or the built-in one without a path, with or without its
+// extensions. Must be called before anything uses current().
+pub fn load(path: Option<&str>, extensions: bool) {
+    let (path, text) = match path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => (path, text),
+            Err(e) => {
+                error!("Couldn't read \"{}\": {}", path, e);
+                std::process::exit(exitcode::IO);
+            }
+        },
+        None => ("isa.toml", DEFAULT_ISA.to_string()),
+    };
+    let isa = match Isa::parse(&text, extensions) {
+        Ok(isa) => isa,
+        Err(e) => isa_error(path, &e),
+    };
+    if ISA.set(isa).is_err() {
+        error!("The ISA was already in use before it was loaded");
+        std::process::exit(exitcode::USAGE);
+    }
+}
+
+pub fn current() -> &'static Isa {
+    ISA.get_or_init(|| match Isa::parse(DEFAULT_ISA, false) {
+        Ok(isa) => isa,
+        Err(e) => isa_error("isa.toml", &e),
+    })
+}
+
+fn isa_error(path: &str, message: &str) -> ! {
+    error!("{}: {}", path, message);
+    std::process::exit(exitcode::USAGE);
+}
+
+impl Isa {
+    fn parse(text: &str, extensions: bool) -> Result<Self, String> {
+        let file: IsaFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        for (name, field) in &file.fields {
+            if field.width == 0 || field.lsb + field.width > 32 {
+                return Err(format!("field {} doesn't fit in a 32-bit word", name));
+            }
+        }
+        let field = |name: &str| match file.fields.get(name) {
+            Some(field) => Ok(*field),
+            None => Err(format!("there's no {} field", name)),
+        };
+        let opcode = field("opcode")?;
+        if field("imm")?.width > 16 {
+            return Err("the imm field is wider than 16 bits".to_string());
+        }
+        let registers = file.registers;
+        let reg_max = registers
+            .count
+            .checked_sub(1)
+            .ok_or("there are no registers")?;
+        let special = [
+            ("zero", registers.zero),
+            ("link", registers.link),
+            ("stack", registers.stack),
+        ];
+        let special = special
+            .iter()
+            .filter_map(|(name, reg)| reg.as_ref().map(|reg| (*name, reg)));
+        let aliases = registers
+            .aliases
+            .iter()
+            .map(|(name, reg)| (name.as_str(), reg));
+        for (name, reg) in special.chain(aliases) {
+            if *reg > reg_max {
+                return Err(format!(
+                    "{} is register {}, but there are only {}",
+                    name, reg, registers.count
+                ));
+            }
+        }
+
+        let mut encodings: Vec<Encoding> = Vec::new();
+        for entry in &file.instructions {
+            let context = |e: String| {
+                format!(
+                    "{} {}: {}",
+                    entry.opcode,
+                    entry.mnemonic.as_deref().unwrap_or("UNDEFINED"),
+                    e
+                )
+            };
+            let code = match u32::from_str_radix(&entry.opcode, 2) {
+                Ok(code) if entry.opcode.len() == opcode.width as usize => code,
+                _ => {
+                    return Err(context(format!(
+                        "opcode isn't {} binary digits",
+                        opcode.width
+                    )))
+                }
+            };
+            if encodings.iter().any(|encoding| encoding.opcode == code) {
+                return Err(context("opcode is listed twice".to_string()));
+            }
+            let operation = match (&entry.mnemonic, &entry.operation) {
+                (Some(_), Some(name)) => Some(Operation::parse(entry, name).map_err(context)?),
+                (None, None) => None,
+                (Some(_), None) => return Err(context("no operation".to_string())),
+                (None, Some(_)) => return Err(context("no mnemonic".to_string())),
+            };
+            let shape = parse_shape(&entry.operands, &file.fields, reg_max).map_err(context)?;
+            let encoding = Encoding {
+                opcode: code,
+                mnemonic: entry.mnemonic.as_deref().unwrap_or("").to_uppercase(),
+                aliases: entry
+                    .aliases
+                    .iter()
+                    .map(|alias| alias.to_uppercase())
+                    .collect(),
+                operands: entry.operands.clone(),
+                shape,
+                operation,
+                extension: entry.extension,
+                enabled: extensions || !entry.extension,
+                note: entry.note.clone(),
+            };
+            if operation == Some(Operation::BL) && registers.link.is_none() {
+                return Err(context("there's no link register".to_string()));
+            }
+            if let Some(operation) = operation {
+                let kinds: Vec<SlotKind> = encoding.slots().map(|slot| slot.kind).collect();
+                let fits = kinds.len() == operation.args().len()
+                    && kinds.iter().zip(operation.args()).all(|(kind, arg)| {
+                        matches!(
+                            (arg, kind),
+                            (Arg::Register, SlotKind::Register)
+                                | (Arg::Source, SlotKind::Register | SlotKind::Imm)
+                                | (Arg::Target, SlotKind::Label)
+                                | (Arg::Constant, SlotKind::Const)
+                        )
+                    });
+                if !fits {
+                    return Err(context(format!(
+                        "operands \"{}\" don't suit {:?}",
+                        entry.operands, operation
+                    )));
+                }
+            }
+            encodings.push(encoding);
+        }
+        let mut pseudos = Vec::new();
+        for pseudo in &file.pseudo {
+            let shape = parse_shape(&pseudo.operands, &file.fields, reg_max)
+                .map_err(|e| format!("{}: {}", pseudo.mnemonic, e))?;
+            pseudos.push(Pseudo {
+                mnemonic: pseudo.mnemonic.to_uppercase(),
+                operands: pseudo.operands.clone(),
+                shape,
+                expansion: pseudo.expansion.clone(),
+                extension: pseudo.extension,
+                enabled: extensions || !pseudo.extension,
+            });
+        }
+        Ok(Self {
+            name: file.name,
+            description: file.description,
+            opcode,
+            registers,
+            encodings,
+            pseudos,
+        })
+    }
+
+    // The forms of a mnemonic, in the order they're tried.
+    pub fn forms(&self, mnemonic: &str) -> Vec<&Encoding> {
+        let mnemonic = mnemonic.to_uppercase();
+        self.encodings
+            .iter()
+            .filter(|encoding| encoding.defined())
+            .filter(|encoding| encoding.names().any(|name| *name == mnemonic))
+            .collect()
+    }
+
+    pub fn pseudo(&self, mnemonic: &str) -> Option<&Pseudo> {
+        let mnemonic = mnemonic.to_uppercase();
+        self.pseudos
+            .iter()
+            .find(|pseudo| pseudo.mnemonic == mnemonic)
+    }
+
+    // Includes instructions from extensions that aren't enabled, so that using one gets a
+    // better error than an unexpected label.
+    pub fn is_mnemonic(&self, word: &str) -> bool {
+        let word = word.to_uppercase();
+        self.pseudo(&word).is_some()
+            || self.encodings.iter().any(|encoding| {
+                encoding.operation.is_some() && encoding.names().any(|name| *name == word)
+            })
+    }
+
+    pub fn register(&self, word: &str) -> Option<u8> {
+        let word = word.to_lowercase();
+        if let Some(reg) = self.registers.aliases.get(&word) {
+            return Some(*reg);
+        }
+        let num: u8 = word
+            .strip_prefix(&self.registers.prefix.to_lowercase())?
+            .parse()
+            .ok()?;
+        // "r01" isn't a register
+        (num < self.registers.count
+            && num.to_string().len() + self.registers.prefix.len() == word.len())
+        .then_some(num)
+    }
+
+    // The first alias of a register, in upper case.
+    pub fn alias(&self, reg: u8) -> Option<String> {
+        self.registers
+            .aliases
+            .iter()
+            .find(|(_, alias_reg)| **alias_reg == reg)
+            .map(|(name, _)| name.to_uppercase())
+    }
+
+    // The name a register goes by where aliases are shown: its alias, if it has one.
+    pub fn conventional_name(&self, reg: u8) -> String {
+        self.alias(reg).unwrap_or_else(|| self.register_name(reg))
+    }
+
+    // The zero register goes by its alias, the others by number.
+    pub fn register_name(&self, reg: u8) -> String {
+        match self.alias(reg) {
+            Some(alias) if Some(reg) == self.registers.zero => alias,
+            _ => format!("{}{}", self.registers.prefix.to_uppercase(), reg),
+        }
+    }
+
+    pub fn opcode_string(&self, opcode: u32) -> String {
+        format!("{:0width$b}", opcode, width = self.opcode.width as usize)
+    }
+
+    // The defined encoding for an opcode.
+    pub fn lookup_opcode(&self, opcode: u32) -> Option<&Encoding> {
+        self.encodings
+            .iter()
+            .find(|encoding| encoding.opcode == opcode && encoding.defined())
+    }
+
+    // The first encoding with the instruction's operation whose slots suit its operands.
+    pub fn encoding_for(&self, instruction: &Instruction) -> Option<&Encoding> {
+        let operation = operation(instruction);
+        let args = args(instruction);
+        self.encodings.iter().find(|encoding| {
+            encoding.enabled
+                && encoding.operation == Some(operation)
+                && encoding.slots().zip(&args).all(|(slot, arg)| {
+                    (slot.kind == SlotKind::Register) == matches!(arg, RegImmAddr::Register(_))
+                })
+        })
+    }
+
+    // Whether the instruction has an encoding with room for its immediate or offset.
+    pub fn fits(&self, instruction: &Instruction) -> bool {
+        let encoding = match self.encoding_for(instruction) {
+            Some(encoding) => encoding,
+            None => return false,
+        };
+        encoding
+            .slots()
+            .zip(args(instruction))
+            .all(|(slot, arg)| match arg {
+                // the parser keeps immediates as the bits of the field, so 0xffff is -1
+                RegImmAddr::Imm(imm) => (imm as u16 as u64) < 1 << slot.field.width,
+                RegImmAddr::Address(offset) => {
+                    let half = 1i64 << (slot.field.width - 1);
+                    (-half..half).contains(&(offset as i64))
+                }
+                _ => true,
+            })
+    }
+
+    // The PC-relative offsets the label or const operand of the instruction can hold.
+    pub fn reach(&self, instruction: &Instruction) -> Option<Range<i64>> {
+        let slot = self
+            .encoding_for(instruction)?
+            .slots()
+            .find(|slot| matches!(slot.kind, SlotKind::Label | SlotKind::Const))?;
+        let half = 1i64 << (slot.field.width - 1);
+        Some(-half..half)
+    }
+
+    pub fn encode(&self, instruction: &Instruction) -> Option<u32> {
+        let encoding = self.encoding_for(instruction)?;
+        let mut word = self.opcode.put(encoding.opcode);
+        for (slot, arg) in encoding.slots().zip(args(instruction)) {
+            let value = match arg {
+                RegImmAddr::Register(reg) => reg as u32,
+                RegImmAddr::Imm(num) | RegImmAddr::Address(num) => num as u16 as u32,
+                RegImmAddr::Unresolved(..) => 0,
+            };
+            word |= slot.field.put(value);
+        }
+        Some(word)
+    }
+
+    // Returns None for undefined opcodes and registers that don't exist.
+    pub fn decode(&self, word: u32) -> Option<Instruction> {
+        let encoding = self.lookup_opcode(self.opcode.get(word))?;
+        let mut args = Vec::new();
+        for slot in encoding.slots() {
+            args.push(match slot.kind {
+                SlotKind::Register => {
+                    let reg = slot.field.get(word);
+                    if reg >= self.registers.count as u32 {
+                        return None;
+                    }
+                    RegImmAddr::Register(reg as u8)
+                }
+                SlotKind::Imm => RegImmAddr::Imm(slot.field.get_signed(word)),
+                SlotKind::Label | SlotKind::Const => {
+                    RegImmAddr::Address(slot.field.get_signed(word))
+                }
+            });
+        }
+        Some(build(encoding.operation?, args))
+    }
+}
+
+// Operand words are field names. imm, label and const are values in the imm field, anything
+// else is a register.
+fn parse_shape(
+    operands: &str,
+    fields: &HashMap<String, Field>,
+    reg_max: u8,
+) -> Result<Vec<Shape>, String> {
+    let slot = |word: &str| {
+        let (kind, field) = match word {
+            "imm" => (SlotKind::Imm, "imm"),
+            "label" => (SlotKind::Label, "imm"),
+            "const" => (SlotKind::Const, "imm"),
+            _ => (SlotKind::Register, word),
+        };
+        match fields.get(field) {
+            Some(field) if kind == SlotKind::Register && field.mask() < reg_max as u32 => Err(
+                format!("{} is too narrow to hold register {}", word, reg_max),
+            ),
+            Some(field) => Ok(Slot {
+                name: word.to_string(),
+                kind,
+                field: *field,
+            }),
+            None => Err(format!("\"{}\" isn't a field", word)),
+        }
+    };
+    let mut shape = Vec::new();
+    let mut rest = operands.trim();
+    while !rest.is_empty() {
+        let (operand, tail) = match rest.strip_prefix('[') {
+            Some(inner) => {
+                let (inner, tail) = inner
+                    .split_once(']')
+                    .ok_or(format!("unclosed '[' in \"{}\"", operands))?;
+                let slots = inner
+                    .split(',')
+                    .map(|word| slot(word.trim()))
+                    .collect::<Result<Vec<Slot>, String>>()?;
+                (Shape::Memory(slots), tail)
+            }
+            None => {
+                let (word, tail) = rest.split_once(',').unwrap_or((rest, ""));
+                (Shape::Slot(slot(word.trim())?), tail)
+            }
+        };
+        shape.push(operand);
+        rest = tail.trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Ok(shape)
+}
+
+pub fn operation(instruction: &Instruction) -> Operation {
+    match instruction {
+        Instruction::Halt => Operation::Halt,
+        Instruction::Alu(op, _, _, _) => Operation::Alu(*op),
+        Instruction::Neg(_, _) => Operation::Neg,
+        Instruction::Swap(_, _) => Operation::Swap,
+        Instruction::Ld(_, RegImmAddr::Register(_)) => Operation::Mov,
+        Instruction::Ld(_, _) => Operation::LoadConstant,
+        Instruction::LdMem(num_bytes, sign_extend, _, _, _) => {
+            Operation::Load(*num_bytes, *sign_extend)
+        }
+        Instruction::St(num_bytes, _, _, _) => Operation::Store(*num_bytes),
+        Instruction::B(_) => Operation::B,
+        Instruction::CBZ(_, _) => Operation::CBZ,
+        Instruction::CBNZ(_, _) => Operation::CBNZ,
+        Instruction::BL(_) => Operation::BL,
+        Instruction::BR(_) => Operation::BR,
+    }
+}
+
+// The operands of an instruction in the order the operation takes them.
+pub fn args(instruction: &Instruction) -> Vec<RegImmAddr> {
+    let reg = |reg: &u8| RegImmAddr::Register(*reg);
+    match instruction {
+        Instruction::Halt => vec![],
+        Instruction::Alu(_, rd, rn, src) => vec![reg(rd), reg(rn), src.clone()],
+        Instruction::Neg(rd, src) | Instruction::Ld(rd, src) => vec![reg(rd), src.clone()],
+        Instruction::Swap(rd, rn) => vec![reg(rd), reg(rn)],
+        Instruction::LdMem(_, _, rd, rn, offset) | Instruction::St(_, rd, rn, offset) => {
+            vec![reg(rd), reg(rn), offset.clone()]
+        }
+        Instruction::B(target) | Instruction::BL(target) => vec![target.clone()],
+        Instruction::BR(rn) => vec![reg(rn)],
+        Instruction::CBZ(rn, target) | Instruction::CBNZ(rn, target) => {
+            vec![reg(rn), target.clone()]
+        }
+    }
+}
+
+// The registers an instruction writes.
+pub fn written(instruction: &Instruction) -> Vec<u8> {
+    match instruction {
+        Instruction::Alu(_, rd, _, _)
+        | Instruction::Neg(rd, _)
+        | Instruction::Ld(rd, _)
+        | Instruction::LdMem(_, _, rd, _, _) => vec![*rd],
+        Instruction::Swap(rd, rn) => vec![*rd, *rn],
+        Instruction::BL(_) => current().registers.link.into_iter().collect(),
+        Instruction::Halt
+        | Instruction::St(..)
+        | Instruction::B(_)
+        | Instruction::CBZ(..)
+        | Instruction::CBNZ(..)
+        | Instruction::BR(_) => vec![],
+    }
+}
+
+// ADD zero, zero, 0, what the NOP pseudo-instruction expands to. None without a zero register.
+pub fn nop() -> Option<Instruction> {
+    let zero = current().registers.zero?;
+    Some(Instruction::Alu(AluOp::Add, zero, zero, RegImmAddr::Imm(0)))
+}
+
+// The registers an instruction reads.
+pub fn read(instruction: &Instruction) -> Vec<u8> {
+    let mut regs = match instruction {
+        Instruction::Alu(_, _, rn, _) | Instruction::LdMem(_, _, _, rn, _) => vec![*rn],
+        Instruction::St(_, rd, rn, _) | Instruction::Swap(rd, rn) => vec![*rd, *rn],
+        Instruction::CBZ(rn, _) | Instruction::CBNZ(rn, _) | Instruction::BR(rn) => vec![*rn],
+        Instruction::Halt
+        | Instruction::Neg(..)
+        | Instruction::Ld(..)
+        | Instruction::B(_)
+        | Instruction::BL(_) => vec![],
+    };
+    // the register operand that could have been an immediate or address
+    if let Instruction::Alu(_, _, _, RegImmAddr::Register(rm))
+    | Instruction::Neg(_, RegImmAddr::Register(rm))
+    | Instruction::Ld(_, RegImmAddr::Register(rm))
+    | Instruction::LdMem(_, _, _, _, RegImmAddr::Register(rm))
+    | Instruction::St(_, _, _, RegImmAddr::Register(rm)) = instruction
+    {
+        regs.push(*rm);
+    }
+    regs
+}
+
+// The inverse of operation() and args().
+pub fn build(operation: Operation, args: Vec<RegImmAddr>) -> Instruction {
+    let reg = |i: usize| match args[i] {
+        RegImmAddr::Register(reg) => reg,
+        _ => 0,
+    };
+    let arg = |i: usize| args[i].clone();
+    match operation {
+        Operation::Halt => Instruction::Halt,
+        Operation::Alu(op) => Instruction::Alu(op, reg(0), reg(1), arg(2)),
+        Operation::Neg => Instruction::Neg(reg(0), arg(1)),
+        Operation::Mov | Operation::LoadConstant => Instruction::Ld(reg(0), arg(1)),
+        Operation::Load(num_bytes, sign_extend) => {
+            Instruction::LdMem(num_bytes, sign_extend, reg(0), reg(1), arg(2))
+        }
+        Operation::Store(num_bytes) => Instruction::St(num_bytes, reg(0), reg(1), arg(2)),
+        Operation::Swap => Instruction::Swap(reg(0), reg(1)),
+        Operation::B => Instruction::B(arg(0)),
+        Operation::CBZ => Instruction::CBZ(reg(0), arg(1)),
+        Operation::CBNZ => Instruction::CBNZ(reg(0), arg(1)),
+        Operation::BL => Instruction::BL(arg(0)),
+        Operation::BR => Instruction::BR(reg(0)),
+    }
+}
+
+// Cross-checks the lexer, the encoder and the decoder against the ISA, returning a description
+// of every disagreement.
+pub fn verify() -> Vec<String> {
+    let isa = current();
+    let mut problems = Vec::new();
+    for encoding in &isa.encodings {
+        let opcode = isa.opcode_string(encoding.opcode);
+        let operation = match encoding.operation {
+            Some(operation) if encoding.enabled => operation,
+            _ => continue,
+        };
+        // a sample with distinct field values
+        let sample_args = encoding
+            .slots()
+            .enumerate()
+            .map(|(i, slot)| match slot.kind {
+                SlotKind::Register => RegImmAddr::Register((i + 1) as u8 % isa.registers.count),
+                SlotKind::Imm => RegImmAddr::Imm(7),
+                SlotKind::Label | SlotKind::Const => RegImmAddr::Address(-12),
+            })
+            .collect();
+        let sample = build(operation, sample_args);
+        let name = format!("{} ({} {})", opcode, encoding.mnemonic, encoding.operands);
+        match isa.encode(&sample) {
+            Some(word) if isa.opcode.get(word) != encoding.opcode => problems.push(format!(
+                "{} is never used, {} with the same operation and operands comes first",
+                name,
+                isa.opcode_string(isa.opcode.get(word))
+            )),
+            Some(word) => match isa.decode(word) {
+                Some(decoded) if isa.encode(&decoded) != Some(word) => problems.push(format!(
+                    "{} doesn't survive decoding: {:08x} became {:?}",
+                    name, word, decoded
+                )),
+                Some(_) => (),
+                None => problems.push(format!("{} doesn't decode", name)),
+            },
+            None => problems.push(format!("{} doesn't encode", name)),
+        }
+        for mnemonic in encoding.names() {
+            let lexer = Lexer::new(format!("{}\n", mnemonic.to_lowercase()));
+            let lexed = lexer.tokens.first().map(|token| &token.value);
+            if lexed != Some(&TokenValue::Mnemonic(mnemonic.clone())) {
+                problems.push(format!("the lexer reads {} as {:?}", mnemonic, lexed));
+            }
+        }
+    }
+    for reg in 0..isa.registers.count {
+        let name = isa.register_name(reg);
+        let lexer = Lexer::new(format!("{}\n", name.to_lowercase()));
+        let lexed = lexer.tokens.first().map(|token| &token.value);
+        if lexed != Some(&TokenValue::Register(reg)) {
+            problems.push(format!("the lexer reads {} as {:?}", name, lexed));
+        }
+    }
+    problems
+}
@@ -1,5 +1,10 @@
-use crate::token::{CommentType, DataTypeDirective, Loc, SectionDirective, Token, TokenValue};
+use crate::exitcode;
+use crate::isa;
+use crate::token::{
+    CommentType, DataTypeDirective, Directive, Loc, SectionDirective, Token, TokenValue,
+};
 use log::error;
+use std::ops::Range;
 
 #[derive(Debug, Clone)]
 pub struct Lexer {
@@ -9,11 +14,25 @@ pub struct Lexer {
     start_loc: Loc,
     curr_idx: usize,
     curr_loc: Loc,
+    keep_comments: bool,
+    pub spans: Vec<Range<usize>>, // where each token is in the source
+    // registers written by alias, and the aliases defined as labels, which take precedence
+    aliased: Vec<(usize, String)>,
+    alias_labels: Vec<String>,
 }
 
 #[allow(dead_code)]
 impl Lexer {
     pub fn new(source: String) -> Self {
+        Self::scan(source, false)
+    }
+
+    // Keeps comments as tokens too, so that the spans of the tokens cover the whole source.
+    pub fn lossless(source: String) -> Self {
+        Self::scan(source, true)
+    }
+
+    fn scan(source: String, keep_comments: bool) -> Self {
         let mut l = Self {
             source,
             tokens: Vec::new(),
@@ -21,23 +40,44 @@ impl Lexer {
             start_loc: Loc { line: 1, col: 1 },
             curr_idx: 0,
             curr_loc: Loc { line: 1, col: 1 },
+            keep_comments,
+            spans: Vec::new(),
+            aliased: Vec::new(),
+            alias_labels: Vec::new(),
         };
         l.scan_tokens();
         l
     }
 
-    pub fn emit(&self) {
+    // The source text of the token at index.
+    pub fn text(&self, index: usize) -> &str {
+        &self.source[self.spans[index].clone()]
+    }
+
+    pub fn emit(&self) -> String {
+        let mut text = String::new();
         for token in &self.tokens {
-            println!("{:?}", token);
+            text.push_str(&format!("{:?}\n", token));
         }
+        text
+    }
+
+    // the tokens as JSON, with enums in serde's default externally tagged layout
+    pub fn emit_json(&self) -> String {
+        serde_json::to_string_pretty(&self.tokens).unwrap()
     }
 
     fn add_token(&mut self, token: Token) {
-        self.tokens.push(token)
+        self.tokens.push(token);
+        self.spans.push(self.start_idx..self.curr_idx);
     }
 
+    // '\0' past the end, which ends every token
     fn peek(&self) -> char {
-        self.source.as_bytes()[self.curr_idx] as char
+        match self.source.as_bytes().get(self.curr_idx) {
+            Some(byte) => *byte as char,
+            None => '\0',
+        }
     }
     fn peek_n(&self, n: usize) -> &str {
         if self.is_at_end() {
@@ -72,8 +112,12 @@ impl Lexer {
         }
         self.start_idx = self.curr_idx;
         self.start_loc = self.curr_loc;
-        self.tokens
-            .push(Token::new(self.start_loc, TokenValue::Eof));
+        self.add_token(Token::new(self.start_loc, TokenValue::Eof));
+        for (index, name) in &self.aliased {
+            if self.alias_labels.contains(name) {
+                self.tokens[*index].value = TokenValue::Label(name.clone());
+            }
+        }
     }
 
     fn parse_token(&mut self) {
@@ -139,10 +183,8 @@ impl Lexer {
         match comment_type {
             CommentType::Line => {
                 self.increment_position(1);
-                let mut c = self.peek();
-                while c != '\n' {
+                while !self.is_at_end() && self.peek() != '\n' {
                     self.increment_position(1);
-                    c = self.peek();
                 }
             }
             CommentType::MultiLine => {
@@ -155,6 +197,10 @@ impl Lexer {
                 self.increment_position(2);
             }
         }
+        if self.keep_comments {
+            let text = self.source[self.start_idx..self.curr_idx].to_string();
+            self.add_token(Token::new(self.start_loc, TokenValue::Comment(text)));
+        }
     }
 
     fn parse_word(&mut self) {
@@ -166,56 +212,33 @@ impl Lexer {
             c = self.peek();
         }
 
-        match str.to_lowercase().as_str() {
-            "add" => self.add_token(Token::new(self.start_loc, TokenValue::Add)),
-            "sub" => self.add_token(Token::new(self.start_loc, TokenValue::Sub)),
-            "mul" => self.add_token(Token::new(self.start_loc, TokenValue::Mul)),
-            "div" => self.add_token(Token::new(self.start_loc, TokenValue::Div)),
-            "mod" => self.add_token(Token::new(self.start_loc, TokenValue::Mod)),
-            "asr" => self.add_token(Token::new(self.start_loc, TokenValue::Asr)),
-            "lsl" => self.add_token(Token::new(self.start_loc, TokenValue::Lsl)),
-            "and" => self.add_token(Token::new(self.start_loc, TokenValue::And)),
-            "orr" => self.add_token(Token::new(self.start_loc, TokenValue::Orr)),
-            "neg" => self.add_token(Token::new(self.start_loc, TokenValue::Neg)),
-            "swap" => self.add_token(Token::new(self.start_loc, TokenValue::Swap)),
-            "halt" => self.add_token(Token::new(self.start_loc, TokenValue::Halt)),
-            "ld1" | "ld1s" => self.add_token(Token::new(
-                self.start_loc,
-                TokenValue::Ld(1, str.len() == 4),
-            )),
-            "ld2" | "ld2s" => self.add_token(Token::new(
-                self.start_loc,
-                TokenValue::Ld(2, str.len() == 4),
-            )),
-            "ld4" | "ld4s" => self.add_token(Token::new(
-                self.start_loc,
-                TokenValue::Ld(4, str.len() == 4),
-            )),
-            "ld" | "lds" => self.add_token(Token::new(
+        let isa = isa::current();
+        match isa.register(&str) {
+            _ if isa.is_mnemonic(&str) => self.add_token(Token::new(
                 self.start_loc,
-                TokenValue::Ld(8, str.len() == 3),
-            )),
-            "st1" | "st2" | "st4" | "st" => self.add_token(Token::new(
-                self.start_loc,
-                TokenValue::St(if str.len() == 2 {
-                    8
-                } else {
-                    str.chars().last().unwrap().to_digit(10).unwrap() as u8
-                }),
-            )),
-            "b" => self.add_token(Token::new(self.start_loc, TokenValue::B)),
-            "cbz" => self.add_token(Token::new(self.start_loc, TokenValue::CBZ)),
-            "cbnz" => self.add_token(Token::new(self.start_loc, TokenValue::CBNZ)),
-            "rzr" => self.add_token(Token::new(self.start_loc, TokenValue::Register(7))),
-            "r0" | "r1" | "r2" | "r3" | "r4" | "r5" | "r6" | "r7" => self.add_token(Token::new(
-                self.start_loc,
-                TokenValue::Register(str.chars().last().unwrap().to_digit(10).unwrap() as u8),
+                TokenValue::Mnemonic(str.to_uppercase()),
             )),
+            Some(_) if c == ':' && isa.registers.aliases.contains_key(&str.to_lowercase()) => {
+                self.increment_position(1);
+                self.alias_labels.push(str.clone());
+                self.add_token(Token::new(self.start_loc, TokenValue::LabelDef(str)))
+            }
+            Some(_) if c == ':' => self.error(
+                format!("\"{}\" is a register name and can't be a label", str),
+                self.start_loc.line,
+                self.start_loc.col,
+            ),
+            Some(reg) => {
+                if isa.registers.aliases.contains_key(&str.to_lowercase()) {
+                    self.aliased.push((self.tokens.len(), str));
+                }
+                self.add_token(Token::new(self.start_loc, TokenValue::Register(reg)))
+            }
             _ => {
                 if c == ':' {
                     self.increment_position(1);
                     self.add_token(Token::new(self.start_loc, TokenValue::LabelDef(str)))
-                } else if c.is_whitespace() {
+                } else {
                     self.add_token(Token::new(self.start_loc, TokenValue::Label(str)))
                 }
             }
@@ -230,7 +253,7 @@ impl Lexer {
             "0x" => {
                 self.increment_position(2);
                 let mut c = self.peek();
-                while c.is_digit(16) {
+                while c.is_ascii_hexdigit() {
                     num.push(c);
                     self.increment_position(1);
                     c = self.peek();
@@ -283,12 +306,12 @@ impl Lexer {
             }
             _ => {
                 let mut c = self.peek();
-                while c.is_digit(10) {
+                while c.is_ascii_digit() {
                     num.push(c);
                     self.increment_position(1);
                     c = self.peek();
                 }
-                match u64::from_str_radix(num.as_str(), 10) {
+                match num.parse::<u64>() {
                     Ok(num) => self.add_token(Token::new(self.start_loc, TokenValue::Imm(num))),
                     Err(e) => match e.kind() {
                         IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => self.error(
@@ -327,6 +350,16 @@ impl Lexer {
                 self.start_loc,
                 TokenValue::DataTypeDirective(DataTypeDirective::Char),
             ))
+        } else if self.match_str("asciz") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::DataTypeDirective(DataTypeDirective::Asciz),
+            ))
+        } else if self.match_str("ascii") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::DataTypeDirective(DataTypeDirective::Ascii),
+            ))
         } else if self.match_str("string") {
             self.add_token(Token::new(
                 self.start_loc,
@@ -352,6 +385,51 @@ impl Lexer {
                 self.start_loc,
                 TokenValue::DataTypeDirective(DataTypeDirective::Byte8),
             ))
+        } else if self.match_str("incbin") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Incbin),
+            ))
+        } else if self.match_str("org") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Org),
+            ))
+        } else if self.match_str("section") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Section),
+            ))
+        } else if self.match_str("space") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Space),
+            ))
+        } else if self.match_str("req") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Req),
+            ))
+        } else if self.match_str("unreq") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Unreq),
+            ))
+        } else if self.match_str("stack") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Stack),
+            ))
+        } else if self.match_str("struct") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Struct),
+            ))
+        } else if self.match_str("ends") {
+            self.add_token(Token::new(
+                self.start_loc,
+                TokenValue::Directive(Directive::Ends),
+            ))
         } else {
             self.error(
                 "Unknown directive".to_string(),
@@ -363,67 +441,51 @@ impl Lexer {
 
     fn parse_char(&mut self) {
         self.increment_position(1);
-        let mut c = self.peek();
-        match c {
-            '\n' | '\r' | '\t' | '\0' | '\'' => {
-                self.error(
-                    "Invalid character literal".to_string(),
-                    self.curr_loc.line,
-                    self.curr_loc.col,
-                );
-            }
-            '\\' => {
-                self.increment_position(1);
-                c = self.peek();
-                match c {
-                    'n' => self.add_token(Token::new(self.start_loc, TokenValue::Char('\n'))),
-                    'r' => self.add_token(Token::new(self.start_loc, TokenValue::Char('\r'))),
-                    't' => self.add_token(Token::new(self.start_loc, TokenValue::Char('\t'))),
-                    '0' => self.add_token(Token::new(self.start_loc, TokenValue::Char('\0'))),
-                    '\'' => self.add_token(Token::new(self.start_loc, TokenValue::Char('\''))),
-                    _ => self.error(
-                        "Invalid escape sequence".to_string(),
-                        self.curr_loc.line,
-                        self.curr_loc.col,
-                    ),
-                }
-                self.increment_position(1);
-            }
+        if self.is_at_end() {
+            self.error(
+                "Unterminated character literal".to_string(),
+                self.start_loc.line,
+                self.start_loc.col,
+            );
+        }
+        let ch = match self.peek() {
+            '\n' | '\r' | '\t' | '\0' | '\'' => self.error(
+                "Invalid character literal".to_string(),
+                self.curr_loc.line,
+                self.curr_loc.col,
+            ),
+            '\\' => self.parse_escape() as char,
+            c if !c.is_ascii() => self.error(
+                "Non-ASCII character in character literal".to_string(),
+                self.curr_loc.line,
+                self.curr_loc.col,
+            ),
             c => {
                 self.increment_position(1);
-                self.add_token(Token::new(self.start_loc, TokenValue::Char(c)));
+                c
             }
+        };
+        if self.is_at_end() || self.peek() != '\'' {
+            self.error(
+                "Unterminated character literal".to_string(),
+                self.start_loc.line,
+                self.start_loc.col,
+            );
         }
         self.increment_position(1);
+        self.add_token(Token::new(self.start_loc, TokenValue::Char(ch)));
     }
+
     fn parse_string(&mut self) {
         self.increment_position(1);
-        let mut str = String::new();
-        while self.peek() != '"' && !self.is_at_end() {
-            let c = self.peek();
-            if c == '\\' {
-                if self.match_str("\\0") {
-                    str.push('\0');
-                } else if self.match_str("\\n") {
-                    str.push('\n');
-                } else if self.match_str("\\r") {
-                    str.push('\r');
-                } else if self.match_str("\\t") {
-                    str.push('\t');
-                } else if self.match_str("\\\\") {
-                    str.push('\\');
-                } else if self.match_str("\\\"") {
-                    str.push('"');
-                } else {
-                    self.error(
-                        "Invalid escape sequence".to_string(),
-                        self.curr_loc.line,
-                        self.curr_loc.col,
-                    );
-                }
-                self.increment_position(2);
+        // bytes rather than a String so that \xNN escapes above 0x7f stay single bytes.
+        let mut bytes: Vec<u8> = Vec::new();
+        while !self.is_at_end() && self.peek() != '"' {
+            if self.peek() == '\\' {
+                let byte = self.parse_escape();
+                bytes.push(byte);
             } else {
-                str.push(c);
+                bytes.push(self.source.as_bytes()[self.curr_idx]);
                 self.increment_position(1);
             }
         }
@@ -431,18 +493,79 @@ impl Lexer {
         if self.is_at_end() {
             self.error(
                 "Unterminated string".to_string(),
-                self.curr_loc.line,
-                self.curr_loc.col,
+                self.start_loc.line,
+                self.start_loc.col,
             );
         }
 
         self.increment_position(1);
-        self.add_token(Token::new(self.start_loc, TokenValue::String(str)));
+        self.add_token(Token::new(self.start_loc, TokenValue::String(bytes)));
+    }
+
+    // Consumes an escape sequence starting at the '\\' and returns the byte it stands for.
+    // Supports \n \r \t \\ \" \' as well as \xNN (1-2 hex digits) and \NNN (1-3 octal digits).
+    fn parse_escape(&mut self) -> u8 {
+        self.increment_position(1);
+        if self.is_at_end() {
+            self.error(
+                "Unterminated escape sequence".to_string(),
+                self.curr_loc.line,
+                self.curr_loc.col,
+            );
+        }
+        let escape_loc = self.curr_loc;
+        let mut num = String::new();
+        match self.peek() {
+            'n' | 'r' | 't' | '\\' | '"' | '\'' => {
+                let byte = match self.peek() {
+                    'n' => b'\n',
+                    'r' => b'\r',
+                    't' => b'\t',
+                    c => c as u8,
+                };
+                self.increment_position(1);
+                byte
+            }
+            'x' => {
+                self.increment_position(1);
+                while num.len() < 2 && !self.is_at_end() && self.peek().is_ascii_hexdigit() {
+                    num.push(self.peek());
+                    self.increment_position(1);
+                }
+                match u8::from_str_radix(num.as_str(), 16) {
+                    Ok(byte) => byte,
+                    Err(_) => self.error(
+                        "Incomplete hex escape sequence".to_string(),
+                        escape_loc.line,
+                        escape_loc.col,
+                    ),
+                }
+            }
+            '0'..='7' => {
+                while num.len() < 3 && !self.is_at_end() && ('0'..='7').contains(&self.peek()) {
+                    num.push(self.peek());
+                    self.increment_position(1);
+                }
+                match u8::from_str_radix(num.as_str(), 8) {
+                    Ok(byte) => byte,
+                    Err(_) => self.error(
+                        "Octal escape sequence exceeds 8 bits".to_string(),
+                        escape_loc.line,
+                        escape_loc.col,
+                    ),
+                }
+            }
+            _ => self.error(
+                "Invalid escape sequence".to_string(),
+                escape_loc.line,
+                escape_loc.col,
+            ),
+        }
     }
 
-    fn error(&self, message: String, line: u32, col: u32) {
+    fn error(&self, message: String, line: u32, col: u32) -> ! {
         error!("{} at {}:{}", message, line, col);
-        std::process::exit(1);
+        std::process::exit(exitcode::SOURCE);
     }
 
     fn is_at_end(&self) -> bool {
@@ -451,7 +574,7 @@ impl Lexer {
 
     fn increment_position(&mut self, n: usize) {
         for _ in 0..n {
-            if self.source.chars().nth(self.curr_idx).unwrap() == '\n' {
+            if self.source.as_bytes()[self.curr_idx] == b'\n' {
                 self.curr_idx += 1;
                 self.curr_loc.line += 1;
                 self.curr_loc.col = 1;
@@ -0,0 +1,235 @@
+use crate::bounds;
+use crate::cfg::{self, Cfg};
+use crate::isa;
+use crate::parser::{AluOp, Instruction, Parser, RegImmAddr};
+use crate::token::Loc;
+use clap::ValueEnum;
+use log::{error, warn};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Lint {
+    /// Writes to the zero register, which are thrown away
+    ZeroWrite,
+    /// Reads of registers that nothing on any path from the entry point writes
+    UninitRead,
+    /// DIV or MOD by an immediate 0
+    DivByZero,
+    /// Shifts by an immediate above 63
+    ShiftRange,
+    /// Running past the last instruction of the text section without a HALT
+    FallThrough,
+    /// SWAP of a register with itself
+    SwapSame,
+    /// Code that no path from the entry point reaches
+    Unreachable,
+    /// Loops that can never reach a HALT
+    InfiniteLoop,
+    /// Loads and stores that can reach outside the memory, the data section or their buffer.
+    /// Addresses nothing in the program bounds, like an index that runs until a 0 is read, aren't
+    /// checked
+    MemoryBounds,
+}
+
+impl Lint {
+    pub fn name(&self) -> String {
+        self.to_possible_value().unwrap().get_name().to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+// Every lint warns unless it's been allowed or denied.
+#[derive(Debug, Default)]
+pub struct Levels(HashMap<Lint, Level>);
+
+impl Levels {
+    pub fn set(&mut self, lints: &[Lint], level: Level) {
+        for lint in lints {
+            self.0.insert(*lint, level);
+        }
+    }
+
+    pub fn get(&self, lint: Lint) -> Level {
+        self.0.get(&lint).copied().unwrap_or(Level::Warn)
+    }
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub lint: Lint,
+    pub loc: Loc,
+    pub message: String,
+}
+
+// Runs every lint over the parsed program, in address order.
+pub fn check(parser: &Parser) -> Vec<Finding> {
+    let isa = isa::current();
+    let instructions = &parser.instructions;
+    let base = parser.memory_map.text.base;
+    // the registers that may have been written on some path to each instruction, None for the
+    // instructions no path reaches
+    let bit = |reg: u8| 1u64 << reg;
+    let mut written: Vec<Option<u64>> = vec![None; instructions.len()];
+    let mut work: Vec<usize> = Vec::new();
+    if !instructions.is_empty() {
+        written[0] = Some(isa.registers.zero.map(bit).unwrap_or(0));
+        work.push(0);
+    }
+    let mut falls_through: Vec<usize> = Vec::new();
+    while let Some(index) = work.pop() {
+        let out = isa::written(&instructions[index])
+            .into_iter()
+            .fold(written[index].unwrap(), |set, reg| set | bit(reg));
+        for next in cfg::flow(instructions, base, index) {
+            if next == instructions.len() {
+                if !falls_through.contains(&index) {
+                    falls_through.push(index);
+                }
+                continue;
+            }
+            let merged = written[next].unwrap_or(0) | out;
+            if written[next] != Some(merged) {
+                written[next] = Some(merged);
+                work.push(next);
+            }
+        }
+    }
+
+    let mut findings: Vec<Finding> = Vec::new();
+    for (index, instruction) in instructions.iter().enumerate() {
+        // padding left by .org
+        let loc = match parser.instruction_locs[index] {
+            Some(loc) => loc,
+            None => continue,
+        };
+        let addr = base + index * 4;
+        let name = |reg: u8| parser.register_name(reg, addr);
+        let mut find = |lint: Lint, message: String| findings.push(Finding { lint, loc, message });
+
+        if let Some(zero) = isa.registers.zero {
+            if isa::written(instruction).contains(&zero) && Some(instruction) != isa::nop().as_ref()
+            {
+                find(
+                    Lint::ZeroWrite,
+                    format!("Write to {}, which throws it away", name(zero)),
+                );
+            }
+        }
+        if let Some(set) = written[index] {
+            // each register once per instruction, however many operands read it
+            let mut reported: u64 = 0;
+            for reg in isa::read(instruction) {
+                if set & bit(reg) == 0 && reported & bit(reg) == 0 {
+                    reported |= bit(reg);
+                    find(
+                        Lint::UninitRead,
+                        format!("{} is read before anything writes it", name(reg)),
+                    );
+                }
+            }
+        }
+        match instruction {
+            Instruction::Alu(op @ (AluOp::Div | AluOp::Mod), _, _, RegImmAddr::Imm(0)) => find(
+                Lint::DivByZero,
+                format!("{} by 0", format!("{:?}", op).to_uppercase()),
+            ),
+            Instruction::Alu(
+                op @ (AluOp::Lsl | AluOp::Lsr | AluOp::Asr),
+                _,
+                _,
+                RegImmAddr::Imm(amount),
+            ) if *amount as u16 > 63 => find(
+                Lint::ShiftRange,
+                format!(
+                    "{} by {}, more than the 63 bits a register can shift",
+                    format!("{:?}", op).to_uppercase(),
+                    *amount as u16
+                ),
+            ),
+            Instruction::Swap(rd, rn) if rd == rn => find(
+                Lint::SwapSame,
+                format!("SWAP of {} with itself does nothing", name(*rd)),
+            ),
+            _ => (),
+        }
+        if falls_through.contains(&index) {
+            find(
+                Lint::FallThrough,
+                "Execution can run past the end of the text section without a HALT".to_string(),
+            );
+        }
+    }
+
+    let graph = Cfg::new(instructions, base);
+    let first_loc = |block: usize| -> Option<Loc> {
+        let block = &graph.blocks[block];
+        parser.instruction_locs[block.start..block.end]
+            .iter()
+            .find_map(|loc| *loc)
+    };
+    // an unreachable run of blocks is reported once, at its first source line
+    let mut reported = false;
+    for block in 0..graph.blocks.len() {
+        if graph.reachable[block] {
+            reported = false;
+            continue;
+        }
+        if let (false, Some(loc)) = (reported, first_loc(block)) {
+            reported = true;
+            findings.push(Finding {
+                lint: Lint::Unreachable,
+                loc,
+                message: "Unreachable code".to_string(),
+            });
+        }
+    }
+    for block in graph.infinite_loops() {
+        if let Some(loc) = first_loc(block) {
+            findings.push(Finding {
+                lint: Lint::InfiniteLoop,
+                loc,
+                message: "Loop with no way to reach a HALT".to_string(),
+            });
+        }
+    }
+    for (index, message) in bounds::check(parser, &graph) {
+        if let Some(loc) = parser.instruction_locs[index] {
+            findings.push(Finding {
+                lint: Lint::MemoryBounds,
+                loc,
+                message,
+            });
+        }
+    }
+    findings.sort_by_key(|finding| (finding.loc.line, finding.loc.col));
+    findings
+}
+
+// Logs the findings at their lint's level. Returns whether any of them was denied.
+pub fn report(findings: &[Finding], levels: &Levels) -> bool {
+    let mut denied = false;
+    for finding in findings {
+        let text = format!(
+            "{} at {}:{} [{}]",
+            finding.message,
+            finding.loc.line,
+            finding.loc.col,
+            finding.lint.name()
+        );
+        match levels.get(finding.lint) {
+            Level::Allow => (),
+            Level::Warn => warn!("{}", text),
+            Level::Deny => {
+                error!("{}", text);
+                denied = true;
+            }
+        }
+    }
+    denied
+}
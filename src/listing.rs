@@ -0,0 +1,127 @@
+use crate::memmap::MemoryRegion;
+use crate::parser::Parser;
+use crate::token::Loc;
+use crate::txtfilegen::encode_instruction;
+use std::collections::BTreeMap;
+
+// Builds a classic assembler listing: the constant pool, the data section and the text section,
+// each entry with its address, contents and the source line that produced it. Instruction words
+// are split into their TNNNNAW/imm/rm/rn/rd fields.
+pub fn generate_listing(source_name: &str, source: &str, parser: &Parser) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let source_line = |loc: Loc| -> String {
+        lines
+            .get(loc.line as usize - 1)
+            .map(|line| line.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    let mut labels: BTreeMap<usize, Vec<&String>> = BTreeMap::new();
+    for (label, addr) in &parser.mapping {
+        labels.entry(*addr).or_default().push(label);
+    }
+    for names in labels.values_mut() {
+        names.sort();
+    }
+    let labels_in = |region: MemoryRegion, start: usize, end: usize| -> Vec<String> {
+        labels
+            .range(start..end)
+            .filter(|(addr, _)| region.base <= **addr && **addr <= region.end())
+            .flat_map(|(addr, names)| {
+                names
+                    .iter()
+                    .map(move |name| format!("{:04x}  {}:", addr, name))
+            })
+            .collect()
+    };
+
+    let map = &parser.memory_map;
+    let mut listing = format!("{}\n\n", source_name);
+
+    listing.push_str("Constant pool\n");
+    listing.push_str("ADDR  VALUE             LINE  LABEL/VALUE       SOURCE\n");
+    for slot in &parser.constant_slots {
+        let offset = slot.addr - map.constant_pool.base;
+        let mut value = [0u8; 8];
+        value.copy_from_slice(&parser.constant_pool[offset..offset + 8]);
+        let value = u64::from_le_bytes(value);
+        let holds = match &slot.label {
+            Some(label) => label.clone(),
+            None => format!("{:#x}", value),
+        };
+        listing.push_str(&format!(
+            "{:04x}  {:016x}  {:4}  {:16}  {}\n",
+            slot.addr,
+            value,
+            slot.loc.line,
+            holds,
+            source_line(slot.loc)
+        ));
+    }
+
+    listing.push_str("\nData section\n");
+    listing.push_str("ADDR  BYTES                     LINE  SOURCE\n");
+    let mut spans = parser.data_spans.clone();
+    spans.sort_by_key(|span| span.addr);
+    let mut last = map.data.base;
+    for span in &spans {
+        for label in labels_in(map.data, last, span.addr + 1) {
+            listing.push_str(&label);
+            listing.push('\n');
+        }
+        last = span.addr + 1;
+        let bytes = &parser.data_section[span.addr - map.data.base..][..span.len];
+        for (i, chunk) in bytes.chunks(8).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            if i == 0 {
+                listing.push_str(&format!(
+                    "{:04x}  {:24}  {:4}  {}\n",
+                    span.addr,
+                    hex.join(" "),
+                    span.loc.line,
+                    source_line(span.loc)
+                ));
+            } else {
+                listing.push_str(&format!("{:04x}  {}\n", span.addr + i * 8, hex.join(" ")));
+            }
+        }
+    }
+    for label in labels_in(map.data, last, map.data.end() + 1) {
+        listing.push_str(&label);
+        listing.push('\n');
+    }
+
+    listing.push_str("\nText section\n");
+    listing.push_str("ADDR  WORD      TNNNNAW IMM              RM  RN  RD   LINE  SOURCE\n");
+    let mut last = map.text.base;
+    for (i, instruction) in parser.instructions.iter().enumerate() {
+        let addr = map.text.base + i * 4;
+        for label in labels_in(map.text, last, addr + 1) {
+            listing.push_str(&label);
+            listing.push('\n');
+        }
+        last = addr + 1;
+        let word = encode_instruction(instruction);
+        let (line, text) = match parser.instruction_locs[i] {
+            Some(loc) => (format!("{:4}", loc.line), source_line(loc)),
+            None => ("    ".to_string(), "(.org fill)".to_string()),
+        };
+        listing.push_str(&format!(
+            "{:04x}  {:08x}  {:07b} {:016b} {:03b} {:03b} {:03b}  {}  {}\n",
+            addr,
+            word,
+            word >> 25,
+            (word >> 9) & 0xffff,
+            (word >> 6) & 0b111,
+            (word >> 3) & 0b111,
+            word & 0b111,
+            line,
+            text
+        ));
+    }
+    for label in labels_in(map.text, last, map.text.end() + 1) {
+        listing.push_str(&label);
+        listing.push('\n');
+    }
+    listing
+}
@@ -1,43 +1,777 @@
+mod bounds;
+mod cfg;
+mod circ;
+mod debuginfo;
+mod disasm;
+mod exitcode;
+mod formats;
+mod formatter;
+mod isa;
 mod lexer;
+mod lint;
+mod listing;
+mod mapfile;
+mod memmap;
+mod output;
 mod parser;
+mod peephole;
+mod pipeline;
+mod rewrite;
+mod sim;
 mod token;
 mod txtfilegen;
 
+use clap::{Args, Parser as ClapParser, Subcommand, ValueEnum};
+use formats::{LogisimOptions, OutputFormat};
 use lexer::Lexer;
+use memmap::MemoryMap;
+use output::Output;
 use parser::Parser;
-use std::fs;
+use std::io::Read;
+use std::path::Path;
 
-use log::error;
+use log::{error, info, warn};
+
+#[derive(ClapParser)]
+#[command(
+    name = "cs382cpu",
+    version,
+    about = "Assembler, disassembler and simulator for the RUSaT CPU",
+    after_help = "Running without a subcommand, as in `cs382cpu prog.cry`, is the same as `cs382cpu asm prog.cry`.\n\
+                  Exit codes: 0 success, 1 source error, 2 usage error, 3 I/O error, 4 runtime error."
+)]
+struct Cli {
+    /// Only report errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Report more of what's going on, repeat for more detail
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// ISA description to use instead of the built-in RUSaT one, see isa.toml
+    #[arg(long, global = true, value_name = "FILE")]
+    isa: Option<String>,
+
+    /// Enable the ISA's extensions, such as BL/BR and CALL/RET
+    #[arg(long, global = true)]
+    isa_ext: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assemble a program into data and text section images
+    Asm(Box<AsmArgs>),
+    /// Disassemble a text section image
+    Disasm(DisasmArgs),
+    /// Assemble a program and simulate it until it halts
+    Run(RunArgs),
+    /// Assemble a program without writing anything
+    Check(CheckArgs),
+    /// Reformat a program
+    Fmt(FmtArgs),
+    /// Print the instruction encodings
+    Isa(IsaArgs),
+}
+
+#[derive(Args)]
+struct MemoryMapArgs {
+    /// Memory map to use instead of the default RUSaT layout
+    #[arg(long, value_name = "FILE")]
+    memory_map: Option<String>,
+
+    /// Place the constant pool
+    #[arg(long, value_name = "BASE,SIZE")]
+    constant_pool: Option<String>,
+
+    /// Place the data section
+    #[arg(long, value_name = "BASE,SIZE")]
+    data: Option<String>,
+
+    /// Place the text section
+    #[arg(long, value_name = "BASE,SIZE")]
+    text: Option<String>,
+}
+
+#[derive(Args)]
+struct PipelineArgs {
+    /// Look for the data hazards of a pipeline with this many stages
+    #[arg(long, value_name = "STAGES", value_parser = clap::value_parser!(u8).range(3..=16))]
+    pipeline: Option<u8>,
+
+    /// How results reach the instructions after them in the pipeline
+    #[arg(long, value_enum, default_value = "none", requires = "pipeline")]
+    forwarding: pipeline::Forwarding,
+}
+
+impl PipelineArgs {
+    fn pipeline(&self) -> Option<pipeline::Pipeline> {
+        self.pipeline.map(|stages| pipeline::Pipeline {
+            stages: stages as usize,
+            forwarding: self.forwarding,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// The lexer's tokens, to <prefix>.tokens
+    Tokens,
+    /// The lexer's tokens as JSON, to <prefix>.tokens.json
+    #[value(name = "tokens=json")]
+    TokensJson,
+    /// The parsed program, to <prefix>.ast
+    Ast,
+    /// The parsed program as JSON, to <prefix>.ast.json
+    #[value(name = "ast=json")]
+    AstJson,
+    /// An assembly listing, to <prefix>.lst
+    Listing,
+    /// The symbol table, to <prefix>.map and <prefix>.map.json
+    Map,
+    /// Source locations for every address, to <prefix>.dbg.json
+    Dbg,
+    /// The control-flow graph as Graphviz DOT, to <prefix>.dot
+    Cfg,
+}
+
+#[derive(Args)]
+struct AsmArgs {
+    /// Source file, or - for stdin
+    input: String,
+
+    #[command(flatten)]
+    memory: MemoryMapArgs,
+
+    /// Directory to write outputs to, or - for stdout
+    #[arg(short, long, value_name = "DIR", default_value = ".")]
+    out_dir: String,
+
+    /// Prefix for output file names [default: the source file name without its extension]
+    #[arg(short, long)]
+    prefix: Option<String>,
+
+    /// Extra outputs to write
+    #[arg(long, value_delimiter = ',')]
+    emit: Vec<Emit>,
+
+    /// Remove instructions that do nothing, fold additions and shorten branches
+    #[arg(short = 'O', long)]
+    optimize: bool,
+
+    #[command(flatten)]
+    pipeline: PipelineArgs,
+
+    /// What to do about the pipeline's hazards
+    #[arg(long, value_enum, default_value = "report", requires = "pipeline")]
+    hazards: pipeline::HazardFix,
+
+    /// Output format: logisim, bin, ihex, srec, readmemh, readmemb, c or rust
+    #[arg(long, value_name = "[data=|text=]FORMAT,...")]
+    format: Option<String>,
+
+    /// Bits per Logisim memory cell: 8, 16, 32 or 64
+    #[arg(long, value_name = "[data=|text=]BITS,...")]
+    cell_width: Option<String>,
+
+    /// Byte order within a Logisim memory cell: little or big
+    #[arg(long, value_name = "[data=|text=]ORDER,...")]
+    endian: Option<String>,
+
+    /// Logisim memory cells per line
+    #[arg(long, value_name = "[data=|text=]N,...")]
+    cells_per_line: Option<String>,
+
+    /// Write runs of equal Logisim cells as N*value: on or off
+    #[arg(long, value_name = "[data=|text=]on|off,...")]
+    rle: Option<String>,
+
+    /// Also load the images into the memories of a Logisim circuit
+    #[arg(long, value_name = "FILE")]
+    circ: Option<String>,
+
+    /// Where to write the updated circuit [default: <prefix>_<circuit file name> in the output directory]
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "circ",
+        conflicts_with = "in_place"
+    )]
+    circ_out: Option<String>,
+
+    /// Update the circuit file itself
+    #[arg(long, requires = "circ")]
+    in_place: bool,
+
+    /// Circuit name or component label of the data memory
+    #[arg(long, value_name = "NAME", default_value = "DataMem")]
+    data_memory: String,
+
+    /// Circuit name or component label of the instruction memory
+    #[arg(long, value_name = "NAME", default_value = "InstructionMem")]
+    text_memory: String,
+}
+
+#[derive(Args)]
+struct DisasmArgs {
+    /// Text section image, Logisim or raw binary, or - for stdin
+    input: String,
+
+    #[command(flatten)]
+    memory: MemoryMapArgs,
+
+    /// Data section image, to show the values loaded from the constant pool
+    #[arg(long, value_name = "FILE")]
+    ram: Option<String>,
+
+    /// Symbol file (.map.json) from `asm --emit map`, for label names
+    #[arg(long, value_name = "FILE")]
+    symbols: Option<String>,
+
+    /// The Logisim images use big endian cells
+    #[arg(long)]
+    big_endian: bool,
+
+    /// Where to write the disassembly, or - for stdout
+    #[arg(short, long, value_name = "FILE", default_value = "-")]
+    output: String,
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Source file, or - for stdin
+    input: String,
+
+    #[command(flatten)]
+    memory: MemoryMapArgs,
+
+    /// Give up after this many instructions
+    #[arg(long, value_name = "N", default_value_t = 10000)]
+    max_steps: usize,
+
+    /// Print every instruction as it executes
+    #[arg(long)]
+    trace: bool,
+
+    /// Print the data memory after the program halts
+    #[arg(long)]
+    dump_ram: bool,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    /// Source file, or - for stdin
+    input: String,
+
+    #[command(flatten)]
+    memory: MemoryMapArgs,
+
+    /// Lints to leave out; every lint warns by default
+    #[arg(short = 'A', long, value_name = "LINT", value_delimiter = ',')]
+    allow: Vec<lint::Lint>,
+
+    /// Lints to report as warnings
+    #[arg(short = 'W', long, value_name = "LINT", value_delimiter = ',')]
+    warn: Vec<lint::Lint>,
+
+    /// Lints to report as errors, which fail the check
+    #[arg(short = 'D', long, value_name = "LINT", value_delimiter = ',')]
+    deny: Vec<lint::Lint>,
+
+    #[command(flatten)]
+    pipeline: PipelineArgs,
+}
+
+#[derive(Args)]
+struct FmtArgs {
+    /// Source files to format in place, or - to format stdin to stdout
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// Only report the files that aren't formatted
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(Args)]
+struct IsaArgs {
+    /// Check the lexer, encoder and decoder against the ISA instead
+    #[arg(long)]
+    verify: bool,
+}
+
+const SUBCOMMANDS: [&str; 7] = ["asm", "disasm", "run", "check", "fmt", "isa", "help"];
 
 fn main() {
-    env_logger::init();
-    let args: Vec<String> = std::env::args().collect();
-    // println!("{:?}", args);
-    if args.len() < 2 {
-        error!("Missing source file!\nUsage: ./target/release/cs382cpu <filename>");
-        std::process::exit(1);
-    }
-    let program: String = match fs::read_to_string(&args[1]) {
+    let mut args: Vec<String> = std::env::args().collect();
+    // `cs382cpu prog.cry` predates the subcommands, keep it working
+    let global_flag = |arg: &&String| {
+        matches!(arg.as_str(), "-q" | "--quiet" | "--verbose" | "--isa-ext")
+            || (arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v'))
+    };
+    let mut rest = args.iter().skip(1);
+    let mut first = None;
+    while let Some(arg) = rest.next() {
+        if arg == "--isa" {
+            rest.next();
+        } else if !global_flag(&arg) && !arg.starts_with("--isa=") {
+            first = Some(arg);
+            break;
+        }
+    }
+    if let Some(first) = first {
+        let is_flag = matches!(first.as_str(), "-h" | "--help" | "-V" | "--version");
+        if !is_flag && !SUBCOMMANDS.contains(&first.as_str()) {
+            args.insert(1, "asm".to_string());
+        }
+    }
+    let cli = match Cli::try_parse_from(args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(if e.use_stderr() { exitcode::USAGE } else { 0 });
+        }
+    };
+
+    let level = match (cli.quiet, cli.verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0) => log::LevelFilter::Warn,
+        (false, 1) => log::LevelFilter::Info,
+        (false, 2) => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    // a filter in RUST_LOG still wins over the flags
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .init();
+
+    isa::load(cli.isa.as_deref(), cli.isa_ext);
+
+    match cli.command {
+        Command::Asm(args) => asm(*args),
+        Command::Disasm(args) => disasm(args),
+        Command::Run(args) => run(args),
+        Command::Check(args) => check(args),
+        Command::Isa(args) => isa_command(args),
+        Command::Fmt(args) => fmt(args),
+    }
+}
+
+fn read_input(input: &str) -> Vec<u8> {
+    let result = if input == "-" {
+        let mut contents = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut contents)
+            .map(|_| contents)
+    } else {
+        std::fs::read(input)
+    };
+    match result {
+        Ok(contents) => contents,
+        Err(e) => {
+            error!("Couldn't read \"{}\": {}", input, e);
+            std::process::exit(exitcode::IO);
+        }
+    }
+}
+
+fn memory_map(args: &MemoryMapArgs) -> MemoryMap {
+    let mut memory_map = match &args.memory_map {
+        Some(file) => MemoryMap::from_file(file),
+        None => MemoryMap::default(),
+    };
+    for (region, value) in [
+        ("constant-pool", &args.constant_pool),
+        ("data", &args.data),
+        ("text", &args.text),
+    ] {
+        if let Some(value) = value {
+            memory_map.set_region(region, value);
+        }
+    }
+    memory_map.validate();
+    memory_map
+}
+
+// Lexes and parses a program, returning its name for messages, the source, the tokens and
+// the parser holding the assembled program.
+fn assemble(input: &str, memory: &MemoryMapArgs) -> (String, String, Lexer, Parser) {
+    let memory_map = memory_map(memory);
+    let program = match String::from_utf8(read_input(input)) {
         Ok(s) => s,
         Err(e) => {
-            error!("{}", e);
-            std::process::exit(1);
-        }
-    };
-    let lexer = Lexer::new(program);
-    // lexer.emit();
-    let parser = Parser::new(lexer.tokens);
-    // parser.emit();
-    let program_name = std::path::Path::new(&args[1])
-        .file_stem()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
-    txtfilegen::generate_files(
-        program_name,
+            error!("Couldn't read \"{}\": {}", input, e);
+            std::process::exit(exitcode::IO);
+        }
+    };
+    let (source_name, source_dir) = if input == "-" {
+        ("<stdin>".to_string(), Path::new("."))
+    } else {
+        (
+            input.to_string(),
+            Path::new(input).parent().unwrap_or(Path::new(".")),
+        )
+    };
+    let lexer = Lexer::new(program.clone());
+    let parser = Parser::new(lexer.tokens.clone(), source_dir, memory_map);
+    (source_name, program, lexer, parser)
+}
+
+fn asm(args: AsmArgs) {
+    let mut data_format = OutputFormat::Logisim(LogisimOptions::default());
+    let mut text_format = OutputFormat::Logisim(LogisimOptions::default());
+    let mut data_logisim = LogisimOptions::default();
+    let mut text_logisim = LogisimOptions::default();
+    let settings = [
+        ("--format", &args.format),
+        ("--cell-width", &args.cell_width),
+        ("--endian", &args.endian),
+        ("--cells-per-line", &args.cells_per_line),
+        ("--rle", &args.rle),
+    ];
+    for (arg, value) in settings {
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+        // either one value for both outputs, or data=<value>,text=<value>
+        for (output, setting) in per_output(value) {
+            let (format, logisim) = match output {
+                "data" => (&mut data_format, &mut data_logisim),
+                "text" => (&mut text_format, &mut text_logisim),
+                _ => {
+                    error!("Unknown output \"{}\", expected data or text", output);
+                    std::process::exit(exitcode::USAGE);
+                }
+            };
+            let valid = match arg {
+                "--format" => match OutputFormat::from_name(setting) {
+                    Some(name) => {
+                        *format = name;
+                        true
+                    }
+                    None => false,
+                },
+                "--cell-width" => match setting {
+                    "8" | "16" | "32" | "64" => {
+                        logisim.cell_bits = setting.parse().unwrap();
+                        true
+                    }
+                    _ => false,
+                },
+                "--endian" => match setting {
+                    "little" | "big" => {
+                        logisim.big_endian = setting == "big";
+                        true
+                    }
+                    _ => false,
+                },
+                "--cells-per-line" => match setting.parse::<usize>() {
+                    Ok(n) if n > 0 => {
+                        logisim.cells_per_line = n;
+                        true
+                    }
+                    _ => false,
+                },
+                _ => match setting {
+                    "on" | "off" => {
+                        logisim.run_length = setting == "on";
+                        true
+                    }
+                    _ => false,
+                },
+            };
+            if !valid {
+                error!("Invalid value \"{}\" for {}", setting, arg);
+                std::process::exit(exitcode::USAGE);
+            }
+        }
+    }
+    for (format, logisim) in [
+        (&mut data_format, data_logisim),
+        (&mut text_format, text_logisim),
+    ] {
+        if let OutputFormat::Logisim(options) = format {
+            *options = logisim;
+        }
+    }
+
+    let (source_name, program, lexer, mut parser) = assemble(&args.input, &args.memory);
+    if args.optimize {
+        peephole::optimize(&mut parser);
+    }
+    if let Some(pipeline) = args.pipeline.pipeline() {
+        match args.hazards {
+            pipeline::HazardFix::Report => {
+                pipeline::report(&parser, &pipeline::hazards(&parser, &pipeline))
+            }
+            fix => pipeline::fix(&mut parser, &pipeline, fix),
+        }
+    }
+    let prefix = match (&args.prefix, args.input.as_str()) {
+        (Some(prefix), _) => prefix.clone(),
+        (None, "-") => "stdin".to_string(),
+        (None, input) => Path::new(input)
+            .file_stem()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string(),
+    };
+    let output = Output::new(&args.out_dir);
+
+    let (ram, rom) = txtfilegen::build_images(
+        &parser.memory_map,
+        &parser.constant_pool,
+        &parser.data_section,
+        &parser.instructions,
+    );
+    txtfilegen::generate_files(&output, &prefix, data_format, text_format, &ram, &rom);
+
+    for emit in &args.emit {
+        match emit {
+            Emit::Tokens => output.write(&format!("{}.tokens", prefix), lexer.emit().as_bytes()),
+            Emit::TokensJson => output.write(
+                &format!("{}.tokens.json", prefix),
+                lexer.emit_json().as_bytes(),
+            ),
+            Emit::Ast => output.write(&format!("{}.ast", prefix), parser.emit().as_bytes()),
+            Emit::AstJson => output.write(
+                &format!("{}.ast.json", prefix),
+                parser.emit_json().as_bytes(),
+            ),
+            Emit::Listing => {
+                let listing = listing::generate_listing(&source_name, &program, &parser);
+                output.write(&format!("{}.lst", prefix), listing.as_bytes());
+            }
+            Emit::Map => {
+                let symbol_map = mapfile::SymbolMap::new(&parser);
+                output.write(&format!("{}.map", prefix), symbol_map.to_text().as_bytes());
+                output.write(
+                    &format!("{}.map.json", prefix),
+                    symbol_map.to_json().as_bytes(),
+                );
+            }
+            Emit::Dbg => {
+                let info = debuginfo::DebugInfo::new(&source_name, &program, &parser);
+                output.write(&format!("{}.dbg.json", prefix), info.to_json().as_bytes());
+            }
+            Emit::Cfg => {
+                let graph = cfg::Cfg::new(&parser.instructions, parser.memory_map.text.base);
+                output.write(
+                    &format!("{}.dot", prefix),
+                    graph.to_dot(&parser, &program).as_bytes(),
+                );
+            }
+        }
+    }
+
+    if let Some(circ_file) = &args.circ {
+        let circ = circ::load_images(
+            circ_file,
+            [
+                (&args.data_memory, &ram, data_logisim.big_endian),
+                (&args.text_memory, &rom, text_logisim.big_endian),
+            ],
+        );
+        match (&args.circ_out, args.in_place) {
+            (Some(out), _) => Output::Dir(".".into()).write(out, circ.as_bytes()),
+            (None, true) => Output::Dir(".".into()).write(circ_file, circ.as_bytes()),
+            (None, false) => {
+                let name = Path::new(circ_file).file_name().unwrap().to_str().unwrap();
+                output.write(&format!("{}_{}", prefix, name), circ.as_bytes())
+            }
+        }
+    }
+}
+
+fn disasm(args: DisasmArgs) {
+    let memory_map = memory_map(&args.memory);
+    let read_image = |file: &str| -> Vec<u8> {
+        match formats::read_image(&read_input(file), args.big_endian) {
+            Ok(image) => image,
+            Err(e) => {
+                error!("Couldn't read image \"{}\": {}", file, e);
+                std::process::exit(exitcode::SOURCE);
+            }
+        }
+    };
+    let rom = read_image(&args.input);
+    let ram = args.ram.as_deref().map(read_image);
+    let symbols: Option<disasm::SymbolFile> =
+        args.symbols
+            .as_deref()
+            .map(|file| match serde_json::from_slice(&read_input(file)) {
+                Ok(symbols) => symbols,
+                Err(e) => {
+                    error!("Invalid symbol file \"{}\": {}", file, e);
+                    std::process::exit(exitcode::SOURCE);
+                }
+            });
+    let text = disasm::disassemble(&rom, memory_map.text.base, ram.as_deref(), symbols.as_ref());
+    match args.output.as_str() {
+        "-" => Output::Stdout.write("disassembly", text.as_bytes()),
+        file => Output::Dir(".".into()).write(file, text.as_bytes()),
+    }
+}
+
+fn run(args: RunArgs) {
+    let (_, _, _, parser) = assemble(&args.input, &args.memory);
+    let (ram, rom) = txtfilegen::build_images(
+        &parser.memory_map,
         &parser.constant_pool,
         &parser.data_section,
         &parser.instructions,
     );
+    let mut machine = sim::Machine::new(&parser.memory_map, ram, rom);
+    let result = machine.run(args.max_steps, args.trace);
+    println!(
+        "{} after {} steps at {:#06x}",
+        if result.is_ok() { "Halted" } else { "Stopped" },
+        machine.steps,
+        machine.pc
+    );
+    print!("{}", machine.dump_registers());
+    if args.dump_ram {
+        print!("{}", machine.dump_ram());
+    }
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(exitcode::RUNTIME);
+    }
+}
+
+fn check(args: CheckArgs) {
+    let (source_name, _, _, parser) = assemble(&args.input, &args.memory);
+    let mut levels = lint::Levels::default();
+    levels.set(&args.allow, lint::Level::Allow);
+    levels.set(&args.warn, lint::Level::Warn);
+    levels.set(&args.deny, lint::Level::Deny);
+    if let Some(pipeline) = args.pipeline.pipeline() {
+        pipeline::report(&parser, &pipeline::hazards(&parser, &pipeline));
+    }
+    if lint::report(&lint::check(&parser), &levels) {
+        std::process::exit(exitcode::SOURCE);
+    }
+    info!("{}: ok", source_name);
+}
+
+fn fmt(args: FmtArgs) {
+    let mut unformatted = false;
+    for input in &args.inputs {
+        let source = match String::from_utf8(read_input(input)) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Couldn't read \"{}\": {}", input, e);
+                std::process::exit(exitcode::IO);
+            }
+        };
+        let formatted = formatter::format(&source, input);
+        if args.check {
+            if formatted != source {
+                let line = source
+                    .lines()
+                    .zip(formatted.lines())
+                    .position(|(old, new)| old != new)
+                    .unwrap_or(source.lines().count().min(formatted.lines().count()));
+                warn!("\"{}\" isn't formatted, from line {}", input, line + 1);
+                unformatted = true;
+            }
+        } else if input == "-" {
+            print!("{}", formatted);
+        } else if formatted != source {
+            if let Err(e) = std::fs::write(input, &formatted) {
+                error!("Couldn't write \"{}\": {}", input, e);
+                std::process::exit(exitcode::IO);
+            }
+            info!("Formatted \"{}\"", input);
+        }
+    }
+    if unformatted {
+        std::process::exit(exitcode::SOURCE);
+    }
+}
+
+// Prints the ISA in the format of instruction_encodings.txt.
+fn isa_command(args: IsaArgs) {
+    let isa = isa::current();
+    if !args.verify {
+        println!(
+            "# {} instruction encodings, generated from the ISA description by `cs382cpu isa`.",
+            isa.name
+        );
+        println!("# Operands are written as in assembly, with field names as placeholders.");
+        println!("#");
+        for line in isa.description.lines() {
+            println!("# {}", line);
+        }
+        for encoding in &isa.encodings {
+            let mut line = format!("{}: ", isa.opcode_string(encoding.opcode));
+            match encoding.operation {
+                Some(_) => {
+                    line.push_str(&encoding.mnemonic);
+                    for alias in &encoding.aliases {
+                        line.push_str(&format!("|{}", alias));
+                    }
+                    line.push_str(&format!(" {}", encoding.operands));
+                }
+                None => line.push_str("UNDEFINED"),
+            }
+            let mut line = line.trim_end().to_string();
+            let mut notes: Vec<&str> = encoding.note.iter().map(|note| note.as_str()).collect();
+            if encoding.extension {
+                notes.push("extension, needs --isa-ext");
+            }
+            if !notes.is_empty() {
+                line.push_str(&format!("  # {}", notes.join("; ")));
+            }
+            println!("{}", line);
+        }
+        if !isa.pseudos.is_empty() {
+            println!("#");
+            println!("# Pseudo-instructions:");
+        }
+        for pseudo in &isa.pseudos {
+            let line = format!(
+                "#   {} -> {}{}",
+                format!("{} {}", pseudo.mnemonic, pseudo.operands).trim(),
+                pseudo.expansion.join("; "),
+                if pseudo.extension {
+                    "  (extension, needs --isa-ext)"
+                } else {
+                    ""
+                }
+            );
+            println!("{}", line);
+        }
+        return;
+    }
+    let problems = isa::verify();
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        std::process::exit(exitcode::SOURCE);
+    }
+    info!("{} encodings checked", isa.encodings.len());
+}
+
+// Splits "value" or "data=value,text=value" into (output, value) pairs, with a bare value
+// applying to both outputs.
+fn per_output(value: &str) -> Vec<(&str, &str)> {
+    let mut settings = Vec::new();
+    for item in value.split(',') {
+        match item.split_once('=') {
+            Some((output, setting)) => settings.push((output, setting)),
+            None => {
+                settings.push(("data", item));
+                settings.push(("text", item));
+            }
+        }
+    }
+    settings
 }
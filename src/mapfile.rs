@@ -0,0 +1,233 @@
+use crate::isa;
+use crate::memmap::MemoryRegion;
+use crate::parser::Parser;
+use crate::token::Loc;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Symbol {
+    pub name: String,
+    pub section: &'static str,
+    pub address: usize,
+    pub size: Option<usize>, // only known for data labels
+    pub references: Vec<Loc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConstantPoolEntry {
+    pub address: usize,
+    pub label: Option<String>,
+    pub value: u64,
+    pub used_at: Loc,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegisterAliasEntry {
+    pub name: String,
+    pub register: u8,
+    pub defined_at: Loc,
+    pub ranges: Vec<(usize, usize)>, // text addresses where the alias was in force, end exclusive
+}
+
+#[derive(Debug, Serialize)]
+pub struct SectionUsage {
+    pub name: &'static str,
+    pub base: usize,
+    pub size: usize,
+    pub used: usize,
+}
+
+// Everything the assembler knows about where things ended up in memory.
+#[derive(Debug, Serialize)]
+pub struct SymbolMap {
+    pub symbols: Vec<Symbol>,
+    pub constant_pool: Vec<ConstantPoolEntry>,
+    pub register_aliases: Vec<RegisterAliasEntry>,
+    pub sections: Vec<SectionUsage>,
+}
+
+impl SymbolMap {
+    pub fn new(parser: &Parser) -> Self {
+        let map = &parser.memory_map;
+        // a label may sit right at the end of its region, e.g. after the last instruction
+        let section_of = |addr: usize| -> &'static str {
+            let in_region = |region: MemoryRegion| region.base <= addr && addr <= region.end();
+            if in_region(map.text) {
+                "text"
+            } else if in_region(map.data) {
+                "data"
+            } else {
+                "constant pool"
+            }
+        };
+
+        let mut symbols: Vec<Symbol> = parser
+            .mapping
+            .iter()
+            .map(|(name, addr)| Symbol {
+                name: name.clone(),
+                section: section_of(*addr),
+                address: *addr,
+                size: parser.label_sizes.get(name).copied(),
+                references: parser.references.get(name).cloned().unwrap_or_default(),
+            })
+            .collect();
+        symbols.sort_by(|a, b| a.address.cmp(&b.address).then(a.name.cmp(&b.name)));
+
+        let constant_pool = parser
+            .constant_slots
+            .iter()
+            .map(|slot| {
+                let offset = slot.addr - map.constant_pool.base;
+                let mut value = [0u8; 8];
+                value.copy_from_slice(&parser.constant_pool[offset..offset + 8]);
+                ConstantPoolEntry {
+                    address: slot.addr,
+                    label: slot.label.clone(),
+                    value: u64::from_le_bytes(value),
+                    used_at: slot.loc,
+                }
+            })
+            .collect();
+
+        let register_aliases = parser
+            .register_aliases
+            .iter()
+            .map(|alias| RegisterAliasEntry {
+                name: alias.name.clone(),
+                register: alias.register,
+                defined_at: alias.loc,
+                ranges: alias.ranges.clone(),
+            })
+            .collect();
+
+        let mut sections = vec![
+            SectionUsage {
+                name: "constant pool",
+                base: map.constant_pool.base,
+                size: map.constant_pool.size,
+                used: parser.constant_pool.len(),
+            },
+            SectionUsage {
+                name: "data",
+                base: map.data.base,
+                size: map.data.size,
+                used: parser.data_section.len(),
+            },
+            SectionUsage {
+                name: "text",
+                base: map.text.base,
+                size: map.text.size,
+                used: parser.instructions.len() * 4,
+            },
+        ];
+        if parser.stack_size > 0 {
+            sections.push(SectionUsage {
+                name: "stack",
+                base: map.data.end() - parser.stack_size,
+                size: parser.stack_size,
+                used: parser.stack_size,
+            });
+        }
+
+        Self {
+            symbols,
+            constant_pool,
+            register_aliases,
+            sections,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_text(&self) -> String {
+        let locs = |locs: &[Loc]| -> String {
+            locs.iter()
+                .map(|loc| format!("{}:{}", loc.line, loc.col))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+
+        let mut text = String::from("Symbols\n");
+        text.push_str(&format!(
+            "{:20} {:14} {:8} {:6} REFERENCES\n",
+            "NAME", "SECTION", "ADDRESS", "SIZE"
+        ));
+        for symbol in &self.symbols {
+            let size = match symbol.size {
+                Some(size) => size.to_string(),
+                None => "-".to_string(),
+            };
+            text.push_str(&format!(
+                "{:20} {:14} {:#06x}   {:6} {}\n",
+                symbol.name,
+                symbol.section,
+                symbol.address,
+                size,
+                locs(&symbol.references)
+            ));
+        }
+
+        text.push_str("\nConstant pool\n");
+        text.push_str(&format!(
+            "{:8} {:18} {:20} USED AT\n",
+            "ADDRESS", "VALUE", "HOLDS"
+        ));
+        for entry in &self.constant_pool {
+            let holds = match &entry.label {
+                Some(label) => label.clone(),
+                None => "(value)".to_string(),
+            };
+            text.push_str(&format!(
+                "{:#06x}   {:#018x} {:20} {}\n",
+                entry.address,
+                entry.value,
+                holds,
+                locs(&[entry.used_at])
+            ));
+        }
+
+        // only programs using .req have any
+        if !self.register_aliases.is_empty() {
+            text.push_str("\nRegister aliases\n");
+            text.push_str(&format!(
+                "{:20} {:8} {:10} RANGES\n",
+                "NAME", "REGISTER", "DEFINED AT"
+            ));
+            for alias in &self.register_aliases {
+                let ranges = alias
+                    .ranges
+                    .iter()
+                    .map(|(start, end)| format!("{:#06x}-{:#06x}", start, end))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                text.push_str(&format!(
+                    "{:20} {:8} {:10} {}\n",
+                    alias.name,
+                    isa::current().register_name(alias.register),
+                    locs(&[alias.defined_at]),
+                    ranges
+                ));
+            }
+        }
+
+        text.push_str("\nSections\n");
+        text.push_str(&format!(
+            "{:14} {:8} {:8} {:8} USAGE\n",
+            "SECTION", "BASE", "SIZE", "USED"
+        ));
+        for section in &self.sections {
+            text.push_str(&format!(
+                "{:14} {:#06x}   {:#06x}   {:#06x}   {}%\n",
+                section.name,
+                section.base,
+                section.size,
+                section.used,
+                section.used * 100 / std::cmp::max(section.size, 1)
+            ));
+        }
+        text
+    }
+}
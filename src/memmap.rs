@@ -0,0 +1,156 @@
+use crate::exitcode;
+use log::error;
+use serde::Deserialize;
+
+// Where each section lives in memory. The constant pool and data section share the RAM that
+// the data image is loaded into (addressed from 0), while the text section is loaded into the
+// ROM, which is addressed relative to the start of the text region.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct MemoryRegion {
+    pub base: usize,
+    pub size: usize,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> usize {
+        self.base + self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct MemoryMap {
+    pub constant_pool: MemoryRegion,
+    pub data: MemoryRegion,
+    pub text: MemoryRegion,
+}
+
+// matches RUSaT.circ: 256 bytes, 64 for the constant pool, 64 for data and 128 (32
+// instructions) for text.
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self {
+            constant_pool: MemoryRegion {
+                base: 0x00,
+                size: 0x40,
+            },
+            data: MemoryRegion {
+                base: 0x40,
+                size: 0x40,
+            },
+            text: MemoryRegion {
+                base: 0x80,
+                size: 0x80,
+            },
+        }
+    }
+}
+
+impl MemoryMap {
+    // Reads a memory map such as
+    //
+    // [constant_pool]
+    // base = 0x000
+    // size = 0x100
+    // [data]
+    // base = 0x100
+    // size = 0x300
+    // [text]
+    // base = 0x400
+    // size = 0x400
+    //
+    // Regions that are left out keep their default placement.
+    pub fn from_file(path: &str) -> Self {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Couldn't read memory map \"{}\": {}", path, e);
+                std::process::exit(exitcode::IO);
+            }
+        };
+        match toml::from_str::<MemoryMap>(&contents) {
+            Ok(map) => map,
+            Err(e) => {
+                error!("Invalid memory map \"{}\": {}", path, e);
+                std::process::exit(exitcode::USAGE);
+            }
+        }
+    }
+
+    // Overrides one region from a "base,size" command line value.
+    pub fn set_region(&mut self, name: &str, value: &str) {
+        let region = match name {
+            "constant-pool" => &mut self.constant_pool,
+            "data" => &mut self.data,
+            "text" => &mut self.text,
+            _ => {
+                error!("Unknown memory region \"{}\"", name);
+                std::process::exit(exitcode::USAGE);
+            }
+        };
+        let parsed: Vec<Option<usize>> = value.split(',').map(parse_number).collect();
+        match parsed.as_slice() {
+            [Some(base), Some(size)] => {
+                region.base = *base;
+                region.size = *size;
+            }
+            _ => {
+                error!(
+                    "Expected \"base,size\" for --{} but found \"{}\"",
+                    name, value
+                );
+                std::process::exit(exitcode::USAGE);
+            }
+        }
+    }
+
+    // the data image covers every address from 0 up to the end of the RAM regions.
+    pub fn ram_size(&self) -> usize {
+        std::cmp::max(self.constant_pool.end(), self.data.end())
+    }
+
+    // the addresses loads and stores can reach: RUSaT.circ's 256-byte RAM, or more if the map
+    // places the RAM regions higher.
+    pub fn memory_size(&self) -> usize {
+        std::cmp::max(self.ram_size(), 0x100)
+    }
+
+    pub fn validate(&self) {
+        let regions = [
+            ("constant pool", self.constant_pool),
+            ("data", self.data),
+            ("text", self.text),
+        ];
+        for (i, (name1, region1)) in regions.iter().enumerate() {
+            for (name2, region2) in &regions[i + 1..] {
+                if region1.base < region2.end() && region2.base < region1.end() {
+                    error!(
+                        "Memory map: {} region ({:#06x}..{:#06x}) overlaps {} region ({:#06x}..{:#06x})",
+                        name1,
+                        region1.base,
+                        region1.end(),
+                        name2,
+                        region2.base,
+                        region2.end()
+                    );
+                    std::process::exit(exitcode::USAGE);
+                }
+            }
+        }
+        if !self.text.base.is_multiple_of(4) || !self.text.size.is_multiple_of(4) {
+            error!("Memory map: text region must be word aligned");
+            std::process::exit(exitcode::USAGE);
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<usize> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = s.strip_prefix("0b") {
+        usize::from_str_radix(bin, 2).ok()
+    } else {
+        s.parse::<usize>().ok()
+    }
+}
@@ -0,0 +1,39 @@
+use crate::exitcode;
+use log::{error, info};
+use std::io::Write;
+use std::path::PathBuf;
+
+// Where generated files go: a directory, or stdout when the output directory is "-". On stdout
+// the files are written one after another in the order they are generated.
+pub enum Output {
+    Dir(PathBuf),
+    Stdout,
+}
+
+impl Output {
+    pub fn new(dir: &str) -> Self {
+        if dir == "-" {
+            return Output::Stdout;
+        }
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            error!("Couldn't create output directory \"{}\": {}", dir, e);
+            std::process::exit(exitcode::IO);
+        }
+        Output::Dir(PathBuf::from(dir))
+    }
+
+    pub fn write(&self, file_name: &str, contents: &[u8]) {
+        let result = match self {
+            Output::Dir(dir) => {
+                let path = dir.join(file_name);
+                info!("Writing {}", path.display());
+                std::fs::write(path, contents)
+            }
+            Output::Stdout => std::io::stdout().write_all(contents),
+        };
+        if let Err(e) = result {
+            error!("Couldn't write \"{}\": {}", file_name, e);
+            std::process::exit(exitcode::IO);
+        }
+    }
+}
@@ -1,13 +1,15 @@
-use crate::token::{DataTypeDirective, SectionDirective, Token, TokenValue};
-use log::{debug, error};
+use crate::exitcode;
+use crate::isa::{self, Operation, Pseudo, Shape, Slot, SlotKind};
+use crate::lexer::Lexer;
+use crate::memmap::MemoryMap;
+use crate::token::{DataTypeDirective, Directive, Loc, SectionDirective, Token, TokenValue};
+use log::{debug, error, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-pub const CONSTANT_POOL_OFFSET: usize = 0x0000;
-pub const DATA_OFFSET: usize = 0x40;
-pub const TEXT_OFFSET: usize = 0x80;
-pub const FILE_LIMIT: usize = 0x100;
-
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum RegImmAddr {
     Register(u8),
     Imm(i16),
@@ -15,20 +17,27 @@ pub enum RegImmAddr {
     Unresolved(String, usize, usize), // label name, current PC, current constant_pool_offset
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum AluOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Asr,
+    Lsl,
+    Lsr,
+    And,
+    Orr,
+    Eor,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum Instruction {
     Halt,
 
-    Add(u8, u8, RegImmAddr),
-    Sub(u8, u8, RegImmAddr),
-    Mul(u8, u8, RegImmAddr),
-    Div(u8, u8, RegImmAddr),
-    Mod(u8, u8, RegImmAddr),
-    Asr(u8, u8, RegImmAddr),
-    Lsl(u8, u8, RegImmAddr),
-
-    And(u8, u8, RegImmAddr),
-    Orr(u8, u8, RegImmAddr),
+    Alu(AluOp, u8, u8, RegImmAddr), // op, rd, rn, (rm | imm)
     Neg(u8, RegImmAddr),
 
     Swap(u8, u8),
@@ -40,11 +49,21 @@ pub enum Instruction {
     B(RegImmAddr),
     CBZ(u8, RegImmAddr),
     CBNZ(u8, RegImmAddr),
+    BL(RegImmAddr), // writes the return address to the link register
+    BR(u8),
+}
+
+// an instruction operand as written, before it is matched against the ISA's operand shapes
+#[derive(Debug)]
+enum Operand {
+    Register(u8),
+    Value(Token), // an immediate, character or label
+    Memory(Vec<Operand>),
 }
 
 #[derive(Debug)]
 pub enum Data {
-    String(String),
+    String(Vec<u8>),
     Char(char),
     Byte1(u8),
     Byte2(u16),
@@ -52,42 +71,167 @@ pub enum Data {
     Byte8(u64),
 }
 
+// an 8-byte constant pool entry, holding either the address of a label or a value
+#[derive(Debug, Clone)]
+pub struct ConstantSlot {
+    pub addr: usize,
+    pub label: Option<String>,
+    pub loc: Loc, // the LD that uses it
+}
+
+// bytes emitted into the data section by one directive
+#[derive(Debug, Clone)]
+pub struct DataSpan {
+    pub addr: usize,
+    pub len: usize,
+    pub loc: Loc,
+}
+
+// a register alias defined by .req, with the text addresses placed while it was in force
+#[derive(Debug, Clone)]
+pub struct RegisterAlias {
+    pub name: String,
+    pub register: u8,
+    pub loc: Loc,
+    pub ranges: Vec<(usize, usize)>,
+}
+
+// a contiguous run of emitted bytes, used to detect overlapping .org/.section placements
+#[derive(Debug, Clone)]
+struct Region {
+    start: usize,
+    end: usize,
+    loc: Loc, // where the run was started
+}
+
 pub struct Parser {
     tokens: Vec<Token>,
     token_idx: usize,
-    mapping: HashMap<String, usize>,
+    include_dir: PathBuf, // relative .incbin paths are resolved against this
+    pub memory_map: MemoryMap,
+    pub mapping: HashMap<String, usize>,
+    pub label_sizes: HashMap<String, usize>, // bytes emitted after a data label
+    pub references: HashMap<String, Vec<Loc>>, // instructions that use each label
+    data_label: Option<String>,              // data label that emitted bytes are counted towards
+    constants: HashMap<String, u64>, // assemble-time constants such as .struct field offsets
     constant_pool_offset: usize,
     data_section_offset: usize,
     text_section_offset: usize,
+    data_regions: Vec<Region>,
+    text_regions: Vec<Region>,
+    region_loc: Loc,
+    current_loc: Loc, // start of the instruction or directive being parsed
     pub instructions: Vec<Instruction>,
+    pub instruction_locs: Vec<Option<Loc>>, // None for padding left by .org
+    pub constant_slots: Vec<ConstantSlot>,
+    pub data_spans: Vec<DataSpan>,
     pub data_section: Vec<u8>,
+    pub stack_size: usize, // reserved at the top of the data region by .stack
+    stack_used: bool,      // set by .stack and by pseudo-instructions that write SP
+    stack_writes: Vec<(Loc, usize)>, // other instructions that write SP, and their addresses
+    expanded_until: usize, // tokens before this index come from a pseudo-instruction
+    pub register_aliases: Vec<RegisterAlias>,
+    active_aliases: HashMap<String, usize>, // by name, into register_aliases
     pub constant_pool: Vec<u8>,
 }
 
 #[allow(dead_code)]
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
+    pub fn new(tokens: Vec<Token>, include_dir: &Path, memory_map: MemoryMap) -> Self {
         let mut p = Self {
             tokens,
             token_idx: 0,
+            include_dir: include_dir.to_path_buf(),
+            memory_map,
             mapping: HashMap::new(),
-            constant_pool_offset: CONSTANT_POOL_OFFSET,
-            data_section_offset: DATA_OFFSET,
-            text_section_offset: TEXT_OFFSET,
+            label_sizes: HashMap::new(),
+            references: HashMap::new(),
+            data_label: None,
+            constants: HashMap::new(),
+            constant_pool_offset: memory_map.constant_pool.base,
+            data_section_offset: memory_map.data.base,
+            text_section_offset: memory_map.text.base,
+            data_regions: Vec::new(),
+            text_regions: Vec::new(),
+            region_loc: Loc { line: 1, col: 1 },
+            current_loc: Loc { line: 1, col: 1 },
             instructions: Vec::new(),
+            instruction_locs: Vec::new(),
+            constant_slots: Vec::new(),
+            data_spans: Vec::new(),
             data_section: Vec::new(),
+            stack_size: 0,
+            stack_used: false,
+            stack_writes: Vec::new(),
+            expanded_until: 0,
+            register_aliases: Vec::new(),
+            active_aliases: HashMap::new(),
             constant_pool: Vec::new(),
         };
         p.parse();
         p
     }
 
-    pub fn emit(&self) {
+    pub fn emit(&self) -> String {
+        let mut text = String::new();
         for instr in &self.instructions {
-            println!("{:?}", instr);
+            text.push_str(&format!("{:?}\n", instr));
         }
-        println!("{:?}", self.data_section);
-        println!("{:?}", self.mapping);
+        text.push_str(&format!("{:?}\n", self.data_section));
+        text.push_str(&format!("{:?}\n", self.mapping));
+        text
+    }
+
+    // The parsed program as JSON: every instruction with its address and source location, the
+    // constant pool and data section bytes, and the label addresses. Enums use serde's default
+    // externally tagged layout, e.g. {"Alu": ["Add", 3, 3, {"Imm": 1}]}.
+    pub fn emit_json(&self) -> String {
+        #[derive(Serialize)]
+        struct Placed<'a> {
+            address: usize,
+            loc: Option<Loc>, // None for .org fill
+            instruction: &'a Instruction,
+        }
+        #[derive(Serialize)]
+        struct Bytes<'a> {
+            base: usize,
+            bytes: &'a [u8],
+        }
+        #[derive(Serialize)]
+        struct Program<'a> {
+            instructions: Vec<Placed<'a>>,
+            constant_pool: Bytes<'a>,
+            data: Bytes<'a>,
+            labels: BTreeMap<&'a String, usize>,
+        }
+
+        let program = Program {
+            instructions: self
+                .instructions
+                .iter()
+                .zip(&self.instruction_locs)
+                .enumerate()
+                .map(|(i, (instruction, loc))| Placed {
+                    address: self.memory_map.text.base + i * 4,
+                    loc: *loc,
+                    instruction,
+                })
+                .collect(),
+            constant_pool: Bytes {
+                base: self.memory_map.constant_pool.base,
+                bytes: &self.constant_pool,
+            },
+            data: Bytes {
+                base: self.memory_map.data.base,
+                bytes: &self.data_section,
+            },
+            labels: self
+                .mapping
+                .iter()
+                .map(|(name, addr)| (name, *addr))
+                .collect(),
+        };
+        serde_json::to_string_pretty(&program).unwrap()
     }
 
     fn is_at_end(&self) -> bool {
@@ -120,22 +264,49 @@ impl Parser {
 
     fn errtok(&self, message: String, token: Token) {
         error!("{} at {}:{}", message, token.loc.line, token.loc.col);
-        std::process::exit(1);
+        std::process::exit(exitcode::SOURCE);
     }
     fn errmsg(&self, message: String) {
         error!("{}", message);
-        std::process::exit(1);
+        std::process::exit(exitcode::SOURCE);
     }
 
     pub fn parse(&mut self) {
+        // __stack_top can be used before the .stack that reserves it
+        let stacks: Vec<Token> = self
+            .tokens
+            .iter()
+            .filter(|token| token.value == TokenValue::Directive(Directive::Stack))
+            .cloned()
+            .collect();
+        if let Some(again) = stacks.get(1) {
+            self.errtok("The stack is already reserved".to_string(), again.clone());
+        }
+        if let Some(directive) = stacks.first() {
+            self.define_constant(
+                "__stack_top".to_string(),
+                self.memory_map.data.end() as u64,
+                directive.clone(),
+            );
+        }
         while !self.is_at_end() {
             let token = self.peek();
             // for now, just skip tokens until a section directive is found. then parse that section
             match &token.value {
-                TokenValue::SectionDirective(section_type) => match section_type {
-                    SectionDirective::Data => self.parse_data_section(),
-                    SectionDirective::Text => self.parse_text_section(),
-                },
+                TokenValue::SectionDirective(section_type) => {
+                    self.region_loc = token.loc;
+                    self.data_label = None;
+                    self.increment_position(1);
+                    match section_type {
+                        SectionDirective::Data => self.parse_data_section(),
+                        SectionDirective::Text => self.parse_text_section(),
+                    }
+                }
+                TokenValue::Directive(Directive::Section) => self.parse_section_directive(),
+                TokenValue::Directive(Directive::Struct) => self.parse_struct_directive(),
+                TokenValue::Directive(Directive::Stack) => self.parse_stack_directive(),
+                TokenValue::Directive(Directive::Req) => self.parse_req_directive(),
+                TokenValue::Directive(Directive::Unreq) => self.parse_unreq_directive(),
                 _ => self.increment_position(1),
             }
         }
@@ -143,6 +314,10 @@ impl Parser {
         // now do second pass and resolve all unresolved labels.
         // if some label token is not in the hashmap already, we have an undefined label!
         self.resolve_labels();
+        self.check_section_sizes();
+        self.check_stack_writes();
+        self.check_overlaps("Data", &self.data_regions);
+        self.check_overlaps("Text", &self.text_regions);
         debug!("{:?}", self.mapping);
         debug!("{:?}", self.constant_pool);
         debug!("{:?}", self.data_section);
@@ -152,78 +327,133 @@ impl Parser {
     fn parse_text_section(&mut self) {
         // keep track of constants, this should immediately resolve, and we can easily calculate
         // relative offset.
-        self.increment_position(1);
         while !self.is_at_end() {
             let t = self.peek();
+            self.current_loc = t.loc;
             match t.value.clone() {
-                TokenValue::SectionDirective(_) | TokenValue::Eof => break,
+                TokenValue::SectionDirective(_)
+                | TokenValue::Directive(Directive::Section)
+                | TokenValue::Eof => break,
                 TokenValue::Whitespace | TokenValue::Newline => self.increment_position(1),
                 TokenValue::LabelDef(label) => {
                     self.increment_position(1);
                     self.mapping.insert(label, self.text_section_offset);
                 }
-                TokenValue::Add
-                | TokenValue::Sub
-                | TokenValue::Mul
-                | TokenValue::Div
-                | TokenValue::Mod
-                | TokenValue::Asr
-                | TokenValue::Lsl
-                | TokenValue::And
-                | TokenValue::Orr => self.parse_reg_reg_immreg_instruction(t.value),
-                TokenValue::Neg => self.parse_neg_instruction(),
-                TokenValue::Swap => self.parse_swap_instruction(),
-                TokenValue::Ld(num_bytes, sign_extension) => {
-                    self.parse_ld_instruction(num_bytes, sign_extension);
+                TokenValue::Directive(Directive::Org) => {
+                    self.parse_org_directive(SectionDirective::Text)
                 }
-                TokenValue::St(num_bytes) => self.parse_st_instruction(num_bytes),
-                TokenValue::Halt => self.parse_halt_instruction(),
-                TokenValue::B => self.parse_branch_instruction(),
-                TokenValue::CBZ | TokenValue::CBNZ => self.parse_cbz_cbnz_instruction(t.value),
+                TokenValue::Mnemonic(mnemonic) => self.parse_instruction(mnemonic),
+                TokenValue::Directive(Directive::Struct) => self.parse_struct_directive(),
+                TokenValue::Directive(Directive::Stack) => self.parse_stack_directive(),
+                TokenValue::Directive(Directive::Req) => self.parse_req_directive(),
+                TokenValue::Directive(Directive::Unreq) => self.parse_unreq_directive(),
                 _ => self.errtok(format!("Unexpected token {:?}", t.value), t),
             }
         }
     }
 
-    fn parse_halt_instruction(&mut self) {
-        self.instructions.push(Instruction::Halt);
-        self.text_section_offset += 4;
-        self.increment_position(1);
-        self.skip_whitespace();
-        match self.peek().value {
-            TokenValue::Newline => self.increment_position(1),
-            _ => self.errtok(
-                format!("Unexpected token {:?}", self.peek().value),
-                self.peek(),
-            ),
+    // places an instruction at the current text offset, padding any gap left by .org with
+    // HALT, which encodes as all zero bytes.
+    fn push_instruction(&mut self, instruction: Instruction) {
+        let index = (self.text_section_offset - self.memory_map.text.base) / 4;
+        if index >= self.instructions.len() {
+            self.instructions
+                .resize_with(index + 1, || Instruction::Halt);
+            self.instruction_locs.resize(index + 1, None);
+        }
+        self.instructions[index] = instruction;
+        self.instruction_locs[index] = Some(self.current_loc);
+        for alias in self.active_aliases.values() {
+            let ranges = &mut self.register_aliases[*alias].ranges;
+            match ranges.last_mut() {
+                Some(range) if range.1 == self.text_section_offset => range.1 += 4,
+                _ => ranges.push((self.text_section_offset, self.text_section_offset + 4)),
+            }
         }
+        Self::mark_placed(
+            &mut self.text_regions,
+            self.text_section_offset,
+            4,
+            self.region_loc,
+        );
     }
 
-    fn parse_branch_instruction(&mut self) {
-        self.increment_position(1);
-        self.skip_whitespace();
-        match self.peek().value {
-            TokenValue::Label(label) => {
-                self.instructions.push(Instruction::B(
-                    RegImmAddr::Unresolved(
-                        label,
-                        self.text_section_offset,
-                        self.constant_pool_offset,
-                    ), // current PC. calculate
-                       // relative offset later
+    // writes bytes at the current data offset, zero-filling any gap left by .org.
+    fn emit_data(&mut self, bytes: &[u8]) {
+        let start = self.data_section_offset - self.memory_map.data.base;
+        let end = start + bytes.len();
+        if end > self.data_section.len() {
+            self.data_section.resize(end, 0);
+        }
+        self.data_section[start..end].copy_from_slice(bytes);
+        if let Some(label) = &self.data_label {
+            *self.label_sizes.get_mut(label).unwrap() += bytes.len();
+        }
+        match self.data_spans.last_mut() {
+            Some(span)
+                if span.loc == self.current_loc
+                    && span.addr + span.len == self.data_section_offset =>
+            {
+                span.len += bytes.len()
+            }
+            _ => self.data_spans.push(DataSpan {
+                addr: self.data_section_offset,
+                len: bytes.len(),
+                loc: self.current_loc,
+            }),
+        }
+        Self::mark_placed(
+            &mut self.data_regions,
+            self.data_section_offset,
+            bytes.len(),
+            self.region_loc,
+        );
+        self.data_section_offset += bytes.len();
+    }
+
+    fn mark_placed(regions: &mut Vec<Region>, start: usize, len: usize, loc: Loc) {
+        if len == 0 {
+            return;
+        }
+        match regions.last_mut() {
+            Some(region) if region.end == start => region.end += len,
+            _ => regions.push(Region {
+                start,
+                end: start + len,
+                loc,
+            }),
+        }
+    }
+
+    fn check_overlaps(&self, section: &str, regions: &[Region]) {
+        let mut sorted = regions.to_vec();
+        sorted.sort_by_key(|region| region.start);
+        for pair in sorted.windows(2) {
+            if pair[1].start < pair[0].end {
+                self.errmsg(format!(
+                    "{} placed at {}:{} ({:#06x}..{:#06x}) overlaps {} placed at {}:{} ({:#06x}..{:#06x})",
+                    section,
+                    pair[1].loc.line,
+                    pair[1].loc.col,
+                    pair[1].start,
+                    pair[1].end,
+                    section.to_lowercase(),
+                    pair[0].loc.line,
+                    pair[0].loc.col,
+                    pair[0].start,
+                    pair[0].end,
                 ));
             }
-            _ => self.errtok(
-                format!(
-                    "Expected label token for branch instruction but found {:?}",
-                    self.peek().value
-                ),
-                self.peek(),
-            ),
         }
-        self.text_section_offset += 4;
+    }
+
+    // .org addr
+    fn parse_org_directive(&mut self, section: SectionDirective) {
+        let directive = self.peek();
         self.increment_position(1);
         self.skip_whitespace();
+        let addr = self.parse_address();
+        self.skip_whitespace();
         match self.peek().value {
             TokenValue::Newline => self.increment_position(1),
             _ => self.errtok(
@@ -231,52 +461,24 @@ impl Parser {
                 self.peek(),
             ),
         }
+        self.set_location(section, addr, directive);
     }
-    fn parse_cbz_cbnz_instruction(&mut self, cb_instruction: TokenValue) {
-        let mut reg: Option<u8> = None;
+
+    // .section .data|.text, addr
+    fn parse_section_directive(&mut self) {
+        let directive = self.peek();
+        let mut section: Option<SectionDirective> = None;
         self.increment_position(1);
         self.skip_whitespace();
         match self.peek().value {
-            TokenValue::Register(register_num) => reg = Some(register_num),
-            _ => self.errtok("Expected a register to check".to_string(), self.peek()),
+            TokenValue::SectionDirective(section_type) => section = Some(section_type),
+            _ => self.errtok("Expected .data or .text".to_string(), self.peek()),
         }
         self.increment_position(1);
         self.skip_whitespace();
         self.expect_comma();
         self.skip_whitespace();
-        match (self.peek().value, cb_instruction) {
-            (TokenValue::Label(label), TokenValue::CBZ) => {
-                self.instructions.push(Instruction::CBZ(
-                    reg.unwrap(),
-                    RegImmAddr::Unresolved(
-                        label,
-                        self.text_section_offset,
-                        self.constant_pool_offset,
-                    ), // current PC. calculate
-                       // relative offset later
-                ));
-            }
-            (TokenValue::Label(label), TokenValue::CBNZ) => {
-                self.instructions.push(Instruction::CBNZ(
-                    reg.unwrap(),
-                    RegImmAddr::Unresolved(
-                        label,
-                        self.text_section_offset,
-                        self.constant_pool_offset,
-                    ), // current PC. calculate
-                       // relative offset later
-                ));
-            }
-            (_, _) => self.errtok(
-                format!(
-                    "Expected label token for branch instruction but found {:?}",
-                    self.peek().value
-                ),
-                self.peek(),
-            ),
-        }
-        self.text_section_offset += 4;
-        self.increment_position(1);
+        let addr = self.parse_address();
         self.skip_whitespace();
         match self.peek().value {
             TokenValue::Newline => self.increment_position(1),
@@ -285,74 +487,426 @@ impl Parser {
                 self.peek(),
             ),
         }
+        let section = section.unwrap();
+        self.set_location(section.clone(), addr, directive);
+        match section {
+            SectionDirective::Data => self.parse_data_section(),
+            SectionDirective::Text => self.parse_text_section(),
+        }
     }
 
-    fn parse_neg_instruction(&mut self) {
-        let mut dst: Option<u8> = None;
-        let mut src: Option<RegImmAddr> = None;
-        self.increment_position(1);
-        self.skip_whitespace();
+    fn parse_address(&mut self) -> usize {
+        let mut addr: Option<usize> = None;
         match self.peek().value {
-            TokenValue::Register(register_num) => dst = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
+            TokenValue::Imm(imm) => addr = Some(imm as usize),
+            TokenValue::Label(name) => addr = Some(self.constant(&name) as usize),
+            _ => self.errtok("Expected an address".to_string(), self.peek()),
         }
         self.increment_position(1);
-        self.skip_whitespace();
-        self.expect_comma();
-        self.skip_whitespace();
-        match self.peek().value {
-            TokenValue::Register(register_num) => src = Some(RegImmAddr::Register(register_num)),
-            TokenValue::Imm(imm) => {
-                if imm > u16::MAX as u64 {
+        addr.unwrap()
+    }
+
+    fn set_location(&mut self, section: SectionDirective, addr: usize, directive: Token) {
+        let region = match section {
+            SectionDirective::Data => self.memory_map.data,
+            SectionDirective::Text => self.memory_map.text,
+        };
+        let (start, end) = (region.base, region.end());
+        if addr < start || addr > end {
+            self.errtok(
+                format!(
+                    "Address {:#06x} is outside the {:?} section ({:#06x}..{:#06x})",
+                    addr, section, start, end
+                ),
+                directive.clone(),
+            );
+        }
+        match section {
+            SectionDirective::Data => self.data_section_offset = addr,
+            SectionDirective::Text => {
+                if !addr.is_multiple_of(4) {
                     self.errtok(
-                        format!("Immediate is too big for instruction {:?}", TokenValue::Neg),
-                        self.peek(),
+                        format!("Text address {:#06x} is not a multiple of 4", addr),
+                        directive.clone(),
                     );
                 }
-                src = Some(RegImmAddr::Imm(imm as i16));
+                self.text_section_offset = addr;
             }
-            TokenValue::Char(ch) => {
-                src = Some(RegImmAddr::Imm(ch as i16));
-            }
-            _ => self.errtok("Expected a register or immediate".to_string(), self.peek()),
         }
-        let dst = dst.unwrap();
-        let src = src.unwrap();
-        self.instructions.push(Instruction::Neg(dst, src));
-        self.text_section_offset += 4;
+        self.region_loc = directive.loc;
+        self.data_label = None;
+    }
+
+    // A mnemonic from the ISA followed by its operands. The first form of the mnemonic whose
+    // operand shape fits what was written is used.
+    fn parse_instruction(&mut self, mnemonic: String) {
+        let mnemonic_token = self.peek();
+        let expanded = self.token_idx < self.expanded_until;
         self.increment_position(1);
         self.skip_whitespace();
+        let operands = self.parse_operands();
         match self.peek().value {
             TokenValue::Newline => self.increment_position(1),
+            TokenValue::Eof => (),
             _ => self.errtok(
                 format!("Unexpected token {:?}", self.peek().value),
                 self.peek(),
             ),
         }
+        let isa = isa::current();
+        if let Some(pseudo) = isa.pseudo(&mnemonic) {
+            if !pseudo.enabled {
+                self.errtok(
+                    format!("{} is an ISA extension, enable it with --isa-ext", mnemonic),
+                    mnemonic_token.clone(),
+                );
+            }
+            if !Self::fits(&pseudo.shape, &operands) {
+                self.errtok(
+                    format!(
+                        "Invalid operands for {}, expected {}",
+                        mnemonic,
+                        format!("{} {}", mnemonic, pseudo.operands).trim()
+                    ),
+                    mnemonic_token.clone(),
+                );
+            }
+            self.expand_pseudo(pseudo, &operands, mnemonic_token.loc);
+            return;
+        }
+        let forms = isa.forms(&mnemonic);
+        if forms.is_empty() {
+            self.errtok(
+                format!("{} is an ISA extension, enable it with --isa-ext", mnemonic),
+                mnemonic_token.clone(),
+            );
+        }
+        let mut encoding: Option<&isa::Encoding> = None;
+        match forms.iter().find(|form| Self::fits(&form.shape, &operands)) {
+            Some(form) => encoding = Some(form),
+            None => {
+                let expected: Vec<String> = forms
+                    .iter()
+                    .map(|form| format!("{} {}", mnemonic, form.operands).trim().to_string())
+                    .collect();
+                self.errtok(
+                    format!(
+                        "Invalid operands for {}, expected {}",
+                        mnemonic,
+                        expected.join(" or ")
+                    ),
+                    mnemonic_token.clone(),
+                )
+            }
+        }
+        let encoding = encoding.unwrap();
+        let mut args: Vec<RegImmAddr> = Vec::new();
+        for (shape, operand) in encoding.shape.iter().zip(&operands) {
+            match (shape, operand) {
+                (Shape::Memory(slots), Operand::Memory(inner)) => {
+                    for (i, slot) in slots.iter().enumerate() {
+                        match inner.get(i) {
+                            Some(operand) => args.push(self.argument(slot, operand, &mnemonic)),
+                            // [rn] is [rn, 0]
+                            None => args.push(RegImmAddr::Imm(0)),
+                        }
+                    }
+                }
+                (Shape::Slot(slot), operand) => args.push(self.argument(slot, operand, &mnemonic)),
+                _ => (),
+            }
+        }
+        let instruction = isa::build(encoding.operation.unwrap(), args);
+        if let Some(stack) = isa.registers.stack {
+            if isa::written(&instruction).contains(&stack) {
+                // loading an address into SP is how it gets set up
+                if expanded {
+                    self.stack_used = true;
+                } else if isa::operation(&instruction) != Operation::LoadConstant {
+                    self.stack_writes
+                        .push((mnemonic_token.loc, self.text_section_offset));
+                }
+            }
+        }
+        self.push_instruction(instruction);
+        self.text_section_offset += 4;
     }
-    fn parse_swap_instruction(&mut self) {
-        let mut reg1: Option<u8> = None;
-        let mut reg2: Option<u8> = None;
-        self.increment_position(1);
-        self.skip_whitespace();
-        match self.peek().value {
-            TokenValue::Register(register_num) => reg1 = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
+
+    // Inserts the expansion of a pseudo-instruction into the token stream in its place, with the
+    // operand words replaced by the tokens that were written for them.
+    fn expand_pseudo(&mut self, pseudo: &Pseudo, operands: &[Operand], loc: Loc) {
+        let mut values: HashMap<&str, Vec<Token>> = HashMap::new();
+        for (shape, operand) in pseudo.shape.iter().zip(operands) {
+            match (shape, operand) {
+                (Shape::Memory(slots), Operand::Memory(inner)) => {
+                    for (i, slot) in slots.iter().enumerate() {
+                        let tokens = match inner.get(i) {
+                            Some(operand) => Self::operand_tokens(operand, loc),
+                            None => vec![Token::new(loc, TokenValue::Imm(0))],
+                        };
+                        values.insert(&slot.name, tokens);
+                    }
+                }
+                (Shape::Slot(slot), operand) => {
+                    values.insert(&slot.name, Self::operand_tokens(operand, loc));
+                }
+                _ => (),
+            }
+        }
+        let mut tokens: Vec<Token> = Vec::new();
+        let lexer = Lexer::new(format!("{}\n", pseudo.expansion.join("\n")));
+        for token in lexer.tokens {
+            match &token.value {
+                TokenValue::Eof => (),
+                TokenValue::Label(name) if values.contains_key(name.as_str()) => {
+                    tokens.extend(values[name.as_str()].iter().cloned())
+                }
+                value => tokens.push(Token::new(loc, value.clone())),
+            }
+        }
+        self.expanded_until = self.expanded_until.max(self.token_idx) + tokens.len();
+        self.tokens.splice(self.token_idx..self.token_idx, tokens);
+    }
+
+    fn operand_tokens(operand: &Operand, loc: Loc) -> Vec<Token> {
+        match operand {
+            Operand::Register(register_num) => {
+                vec![Token::new(loc, TokenValue::Register(*register_num))]
+            }
+            Operand::Value(token) => vec![token.clone()],
+            Operand::Memory(inner) => {
+                let mut tokens = vec![Token::new(loc, TokenValue::LBracket)];
+                for (i, operand) in inner.iter().enumerate() {
+                    if i > 0 {
+                        tokens.push(Token::new(loc, TokenValue::Comma));
+                    }
+                    tokens.extend(Self::operand_tokens(operand, loc));
+                }
+                tokens.push(Token::new(loc, TokenValue::RBracket));
+                tokens
+            }
+        }
+    }
+
+    // operands separated by commas, up to the end of the line
+    fn parse_operands(&mut self) -> Vec<Operand> {
+        let mut operands: Vec<Operand> = Vec::new();
+        if matches!(self.peek().value, TokenValue::Newline | TokenValue::Eof) {
+            return operands;
+        }
+        loop {
+            if self.peek().value == TokenValue::LBracket {
+                self.increment_position(1); // consume the '['
+                self.skip_whitespace();
+                let mut inner = vec![self.parse_operand()];
+                self.skip_whitespace();
+                while self.peek().value == TokenValue::Comma {
+                    self.increment_position(1);
+                    self.skip_whitespace();
+                    inner.push(self.parse_operand());
+                    self.skip_whitespace();
+                }
+                match self.peek().value {
+                    TokenValue::RBracket => self.increment_position(1),
+                    _ => self.errtok("Expected ']'".to_string(), self.peek()),
+                }
+                operands.push(Operand::Memory(inner));
+            } else {
+                operands.push(self.parse_operand());
+            }
+            self.skip_whitespace();
+            if self.peek().value != TokenValue::Comma {
+                return operands;
+            }
+            self.increment_position(1);
+            self.skip_whitespace();
+        }
+    }
+
+    fn parse_operand(&mut self) -> Operand {
+        let token = self.peek();
+        match token.value {
+            TokenValue::Register(register_num) => {
+                self.increment_position(1);
+                return Operand::Register(register_num);
+            }
+            TokenValue::Label(ref name) if self.active_aliases.contains_key(name) => {
+                self.increment_position(1);
+                let alias = &self.register_aliases[self.active_aliases[name]];
+                return Operand::Register(alias.register);
+            }
+            TokenValue::Imm(_) | TokenValue::Char(_) | TokenValue::Label(_) => {
+                self.increment_position(1);
+            }
+            _ => self.errtok(
+                "Expected a register, immediate or label".to_string(),
+                token.clone(),
+            ),
+        }
+        Operand::Value(token)
+    }
+
+    fn fits(shape: &[Shape], operands: &[Operand]) -> bool {
+        let fits_slot = |slot: &Slot, operand: &Operand| match (slot.kind, operand) {
+            (SlotKind::Register, Operand::Register(_)) => true,
+            (SlotKind::Imm | SlotKind::Const, Operand::Value(_)) => true,
+            (SlotKind::Label, Operand::Value(token)) => {
+                matches!(token.value, TokenValue::Label(_))
+            }
+            _ => false,
+        };
+        shape.len() == operands.len()
+            && shape
+                .iter()
+                .zip(operands)
+                .all(|(shape, operand)| match (shape, operand) {
+                    (Shape::Slot(slot), operand) => fits_slot(slot, operand),
+                    (Shape::Memory(slots), Operand::Memory(inner)) => {
+                        // the offset of a memory operand can be left out
+                        let implicit = slots.len() == inner.len() + 1
+                            && slots.last().unwrap().kind == SlotKind::Imm;
+                        (slots.len() == inner.len() || implicit)
+                            && slots
+                                .iter()
+                                .zip(inner)
+                                .all(|(slot, op)| fits_slot(slot, op))
+                    }
+                    _ => false,
+                })
+    }
+
+    // the value of an operand that fits slot
+    fn argument(&mut self, slot: &Slot, operand: &Operand, mnemonic: &str) -> RegImmAddr {
+        let token = match operand {
+            Operand::Register(register_num) => return RegImmAddr::Register(*register_num),
+            Operand::Value(token) => token.clone(),
+            Operand::Memory(_) => return RegImmAddr::Imm(0),
+        };
+        match (slot.kind, &token.value) {
+            (SlotKind::Label, TokenValue::Label(label)) => RegImmAddr::Unresolved(
+                label.clone(),
+                self.text_section_offset,
+                self.constant_pool_offset,
+            ), // current PC. calculate relative offset later
+            (SlotKind::Const, _) => self.pool_constant(&token),
+            (_, value) => {
+                let mut imm: u64 = 0;
+                match value {
+                    TokenValue::Imm(num) => imm = *num,
+                    TokenValue::Char(ch) => imm = *ch as u64,
+                    TokenValue::Label(name) => match self.constants.get(name) {
+                        Some(constant) => imm = *constant,
+                        None => {
+                            self.errtok(format!("\"{}\" is not a constant", name), token.clone())
+                        }
+                    },
+                    _ => (),
+                }
+                if imm > (1u64 << slot.field.width) - 1 {
+                    self.errtok(
+                        format!("Immediate is too big for instruction {}", mnemonic),
+                        token,
+                    );
+                }
+                RegImmAddr::Imm(imm as i16)
+            }
         }
+    }
+
+    // Puts a value in the next constant pool slot and returns the PC-relative offset of the
+    // slot. Label addresses are filled in by resolve_labels.
+    fn pool_constant(&mut self, token: &Token) -> RegImmAddr {
+        let slot = self.constant_pool_offset;
+        let offset = |value: String| {
+            RegImmAddr::Address(self.offset(
+                &Instruction::Ld(0, RegImmAddr::Address(0)),
+                Some(self.current_loc),
+                &format!("the constant pool entry for {}", value),
+                slot,
+                self.text_section_offset,
+            ))
+        };
+        let (value, label, operand) = match &token.value {
+            TokenValue::Label(name) if self.constants.contains_key(name) => (
+                self.constants[name],
+                Some(name.clone()),
+                offset(format!("\"{}\"", name)),
+            ),
+            // since for LD reg, label we need the physical label to be loaded from memory, keep
+            // track of where the label should live and leave space for it
+            TokenValue::Label(name) => (
+                0,
+                Some(name.clone()),
+                RegImmAddr::Unresolved(name.clone(), self.text_section_offset, slot),
+            ),
+            TokenValue::Char(ch) => (*ch as u64, None, offset(format!("{:?}", ch))),
+            TokenValue::Imm(imm) => (*imm, None, offset(imm.to_string())),
+            _ => (0, None, offset("0".to_string())),
+        };
+        self.constant_pool.extend_from_slice(&value.to_le_bytes());
+        self.constant_slots.push(ConstantSlot {
+            addr: slot,
+            label,
+            loc: self.current_loc,
+        });
+        self.constant_pool_offset += 8;
+        operand
+    }
+
+    fn parse_data_section(&mut self) {
+        // also keep track of label definitions. we don't allow label usages in the data section
+        while !self.is_at_end() {
+            let t = self.peek();
+            self.current_loc = t.loc;
+            match t.value.clone() {
+                TokenValue::SectionDirective(_)
+                | TokenValue::Directive(Directive::Section)
+                | TokenValue::Eof => break,
+                TokenValue::Whitespace | TokenValue::Newline => self.increment_position(1),
+                TokenValue::LabelDef(label) => {
+                    self.increment_position(1);
+                    self.mapping.insert(label.clone(), self.data_section_offset);
+                    self.label_sizes.insert(label.clone(), 0);
+                    self.data_label = Some(label);
+                }
+                TokenValue::Directive(Directive::Org) => {
+                    self.parse_org_directive(SectionDirective::Data)
+                }
+                TokenValue::DataTypeDirective(datatype) => self.parse_datatype_directive(datatype),
+                TokenValue::Directive(Directive::Incbin) => self.parse_incbin_directive(),
+                TokenValue::Directive(Directive::Space) => self.parse_space_directive(),
+                TokenValue::Directive(Directive::Struct) => self.parse_struct_directive(),
+                TokenValue::Directive(Directive::Stack) => self.parse_stack_directive(),
+                TokenValue::Directive(Directive::Req) => self.parse_req_directive(),
+                TokenValue::Directive(Directive::Unreq) => self.parse_unreq_directive(),
+                _ => self.errtok(format!("Unexpected token {:?}", t.value), t),
+            }
+        }
+    }
+
+    // .incbin "file" [, offset [, length]]
+    fn parse_incbin_directive(&mut self) {
+        let directive = self.peek();
+        let mut path: Option<String> = None;
         self.increment_position(1);
         self.skip_whitespace();
-        self.expect_comma();
-        self.skip_whitespace();
         match self.peek().value {
-            TokenValue::Register(register_num) => reg2 = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
+            TokenValue::String(bytes) => path = Some(String::from_utf8_lossy(&bytes).to_string()),
+            _ => self.errtok("Expected a file name string".to_string(), self.peek()),
         }
-        let reg1 = reg1.unwrap();
-        let reg2 = reg2.unwrap();
-        self.instructions.push(Instruction::Swap(reg1, reg2));
-        self.text_section_offset += 4;
         self.increment_position(1);
         self.skip_whitespace();
+        let mut args: Vec<usize> = Vec::new();
+        while self.peek().value == TokenValue::Comma && args.len() < 2 {
+            self.increment_position(1);
+            self.skip_whitespace();
+            match self.peek().value {
+                TokenValue::Imm(imm) => args.push(imm as usize),
+                _ => self.errtok("Expected an immediate".to_string(), self.peek()),
+            }
+            self.increment_position(1);
+            self.skip_whitespace();
+        }
         match self.peek().value {
             TokenValue::Newline => self.increment_position(1),
             _ => self.errtok(
@@ -360,67 +914,73 @@ impl Parser {
                 self.peek(),
             ),
         }
+
+        let path = path.unwrap();
+        let full_path = self.include_dir.join(&path);
+        let mut contents: Vec<u8> = Vec::new();
+        match std::fs::read(&full_path) {
+            Ok(bytes) => contents = bytes,
+            Err(e) => self.errtok(
+                format!("Couldn't read \"{}\": {}", full_path.display(), e),
+                directive.clone(),
+            ),
+        }
+        let start = args.first().copied().unwrap_or(0);
+        if start > contents.len() {
+            self.errtok(
+                format!(
+                    "Offset {} is past the end of \"{}\" ({} bytes)",
+                    start,
+                    path,
+                    contents.len()
+                ),
+                directive.clone(),
+            );
+        }
+        let length = args.get(1).copied().unwrap_or(contents.len() - start);
+        if length > contents.len() - start {
+            self.errtok(
+                format!(
+                    "Cannot include {} bytes from offset {} of \"{}\" ({} bytes)",
+                    length,
+                    start,
+                    path,
+                    contents.len()
+                ),
+                directive,
+            );
+        }
+        self.emit_data(&contents[start..start + length]);
     }
-    fn parse_reg_reg_immreg_instruction(&mut self, instruction_op: TokenValue) {
-        let mut dst: Option<u8> = None;
-        let mut src1: Option<u8> = None;
-        let mut src2: Option<RegImmAddr> = None;
-        self.increment_position(1);
-        self.skip_whitespace();
-        match self.peek().value {
-            TokenValue::Register(register_num) => dst = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
+
+    // Looks up an assemble-time constant used in place of an immediate.
+    fn constant(&self, name: &str) -> u64 {
+        match self.constants.get(name) {
+            Some(value) => *value,
+            None => {
+                self.errtok(format!("\"{}\" is not a constant", name), self.peek());
+                0
+            }
         }
-        self.increment_position(1);
-        self.skip_whitespace();
-        self.expect_comma();
-        self.skip_whitespace();
-        match self.peek().value {
-            TokenValue::Register(register_num) => src1 = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
+    }
+
+    fn define_constant(&mut self, name: String, value: u64, token: Token) {
+        if self.constants.contains_key(&name) {
+            self.errtok(format!("Constant \"{}\" is already defined", name), token);
         }
+        self.constants.insert(name, value);
+    }
+
+    // .space n
+    fn parse_space_directive(&mut self) {
         self.increment_position(1);
         self.skip_whitespace();
-        self.expect_comma();
-        self.skip_whitespace();
+        let mut size: Option<usize> = None;
         match self.peek().value {
-            TokenValue::Register(register_num) => src2 = Some(RegImmAddr::Register(register_num)),
-            TokenValue::Imm(imm) => {
-                if imm > u16::MAX as u64 {
-                    self.errtok(
-                        format!("Immediate is too big for instruction {:?}", instruction_op),
-                        self.peek(),
-                    );
-                }
-                src2 = Some(RegImmAddr::Imm(imm as i16));
-            }
-            TokenValue::Char(ch) => {
-                src2 = Some(RegImmAddr::Imm(ch as i16));
-            }
-            _ => self.errtok("Expected a register or immediate".to_string(), self.peek()),
-        }
-        let dst = dst.unwrap();
-        let src1 = src1.unwrap();
-        let src2 = src2.unwrap();
-        match instruction_op {
-            TokenValue::Add => self.instructions.push(Instruction::Add(dst, src1, src2)),
-            TokenValue::Sub => self.instructions.push(Instruction::Sub(dst, src1, src2)),
-            TokenValue::Mul => self.instructions.push(Instruction::Mul(dst, src1, src2)),
-            TokenValue::Div => self.instructions.push(Instruction::Div(dst, src1, src2)),
-            TokenValue::Mod => self.instructions.push(Instruction::Mod(dst, src1, src2)),
-            TokenValue::Asr => self.instructions.push(Instruction::Asr(dst, src1, src2)),
-            TokenValue::Lsl => self.instructions.push(Instruction::Lsl(dst, src1, src2)),
-            TokenValue::And => self.instructions.push(Instruction::And(dst, src1, src2)),
-            TokenValue::Orr => self.instructions.push(Instruction::Orr(dst, src1, src2)),
-            _ => self.errtok(
-                format!(
-                    "Illegal state when parsing {:?} instruction",
-                    instruction_op
-                ),
-                self.peek(),
-            ),
+            TokenValue::Imm(imm) => size = Some(imm as usize),
+            TokenValue::Label(name) => size = Some(self.constant(&name) as usize),
+            _ => self.errtok("Expected a size".to_string(), self.peek()),
         }
-        self.text_section_offset += 4;
         self.increment_position(1);
         self.skip_whitespace();
         match self.peek().value {
@@ -430,82 +990,25 @@ impl Parser {
                 self.peek(),
             ),
         }
+        let size = size.unwrap();
+        self.emit_data(&vec![0; size]);
     }
 
-    fn parse_ld_instruction(&mut self, num_bytes: u8, sign_extension: bool) {
+    // .stack size
+    // reserves size bytes at the top of the data region for PUSH and POP. parse defines the
+    // constant __stack_top to load SP with up front
+    fn parse_stack_directive(&mut self) {
+        let directive = self.peek();
         self.increment_position(1);
         self.skip_whitespace();
-        let mut dst: Option<u8> = None;
+        let mut size: Option<usize> = None;
         match self.peek().value {
-            TokenValue::Register(register_num) => dst = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
+            TokenValue::Imm(imm) => size = Some(imm as usize),
+            TokenValue::Label(name) => size = Some(self.constant(&name) as usize),
+            _ => self.errtok("Expected a size".to_string(), self.peek()),
         }
         self.increment_position(1);
         self.skip_whitespace();
-        self.expect_comma();
-        self.skip_whitespace();
-        match (self.peek().value, num_bytes) {
-            (TokenValue::Register(register_num), 8) => {
-                self.instructions.push(Instruction::Ld(
-                    dst.unwrap(),
-                    RegImmAddr::Register(register_num),
-                ));
-                self.increment_position(1);
-            }
-            (TokenValue::Label(label), 8) => {
-                self.instructions.push(Instruction::Ld(
-                    dst.unwrap(),
-                    RegImmAddr::Unresolved(
-                        label,
-                        self.text_section_offset,
-                        self.constant_pool_offset,
-                    ), // current PC. calculate
-                       // relative offset later
-                ));
-                self.constant_pool
-                    .append(&mut (0 as u64).to_le_bytes().to_vec());
-                self.constant_pool_offset += 8;
-                self.increment_position(1);
-            }
-            (TokenValue::Imm(imm), 8) => {
-                self.instructions.push(Instruction::Ld(
-                    dst.unwrap(),
-                    RegImmAddr::Address(
-                        (self.constant_pool_offset as isize - self.text_section_offset as isize)
-                            as i16,
-                    ),
-                ));
-                self.constant_pool.append(&mut imm.to_le_bytes().to_vec());
-                self.constant_pool_offset += 8;
-                self.increment_position(1);
-            }
-            (TokenValue::Char(ch), 8) => {
-                self.instructions.push(Instruction::Ld(
-                    dst.unwrap(),
-                    RegImmAddr::Address(
-                        (self.constant_pool_offset as isize - self.text_section_offset as isize)
-                            as i16,
-                    ),
-                ));
-                self.constant_pool
-                    .append(&mut (ch as u64).to_le_bytes().to_vec());
-                self.constant_pool_offset += 8;
-                self.increment_position(1);
-            }
-            (TokenValue::LBracket, _) => {
-                let (addr_reg, offset) = self.parse_memory_access();
-                self.instructions.push(Instruction::LdMem(
-                    num_bytes,
-                    sign_extension,
-                    dst.unwrap(),
-                    addr_reg,
-                    offset,
-                ))
-            }
-            (_, _) => self.errtok("Invalid LD instruction syntax".to_string(), self.peek()),
-        }
-        self.text_section_offset += 4;
-        self.skip_whitespace();
         match self.peek().value {
             TokenValue::Newline => self.increment_position(1),
             _ => self.errtok(
@@ -513,28 +1016,48 @@ impl Parser {
                 self.peek(),
             ),
         }
+        let size = size.unwrap();
+        if size > self.memory_map.data.size {
+            self.errtok(
+                format!(
+                    "A {} byte stack doesn't fit in the data region ({} bytes)",
+                    size, self.memory_map.data.size
+                ),
+                directive,
+            );
+        }
+        self.stack_size = size;
+        self.stack_used = true;
     }
-    fn parse_st_instruction(&mut self, num_bytes: u8) {
+
+    // .req name, register
+    fn parse_req_directive(&mut self) {
         self.increment_position(1);
         self.skip_whitespace();
-        let mut src: Option<u8> = None;
-        match self.peek().value {
-            TokenValue::Register(register_num) => src = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
+        let name_token = self.peek();
+        let mut name: Option<String> = None;
+        match name_token.value.clone() {
+            TokenValue::Label(label) if self.active_aliases.contains_key(&label) => self.errtok(
+                format!("\"{}\" is already an alias, .unreq it first", label),
+                name_token.clone(),
+            ),
+            TokenValue::Label(label) => name = Some(label),
+            TokenValue::Register(_) | TokenValue::Mnemonic(_) => self.errtok(
+                "Expected an alias name, not a register or mnemonic".to_string(),
+                name_token.clone(),
+            ),
+            _ => self.errtok("Expected an alias name".to_string(), name_token.clone()),
         }
         self.increment_position(1);
         self.skip_whitespace();
         self.expect_comma();
         self.skip_whitespace();
-        match self.peek().value {
-            TokenValue::LBracket => {
-                let (addr_reg, offset) = self.parse_memory_access();
-                self.instructions
-                    .push(Instruction::St(num_bytes, src.unwrap(), addr_reg, offset))
-            }
-            _ => self.errtok("Invalid ST instruction syntax".to_string(), self.peek()),
+        let register_token = self.peek();
+        let mut register: Option<u8> = None;
+        match self.parse_operand() {
+            Operand::Register(register_num) => register = Some(register_num),
+            _ => self.errtok("Expected a register".to_string(), register_token),
         }
-        self.text_section_offset += 4;
         self.skip_whitespace();
         match self.peek().value {
             TokenValue::Newline => self.increment_position(1),
@@ -543,74 +1066,109 @@ impl Parser {
                 self.peek(),
             ),
         }
+        self.active_aliases
+            .insert(name.clone().unwrap(), self.register_aliases.len());
+        self.register_aliases.push(RegisterAlias {
+            name: name.unwrap(),
+            register: register.unwrap(),
+            loc: name_token.loc,
+            ranges: Vec::new(),
+        });
     }
 
-    fn parse_memory_access(&mut self) -> (u8, RegImmAddr) {
-        self.increment_position(1); // consume the '['
-        self.skip_whitespace();
-        let mut addr_reg: Option<u8> = None;
-        let mut offset: Option<RegImmAddr> = None;
-        match self.peek().value {
-            TokenValue::Register(register_num) => addr_reg = Some(register_num),
-            _ => self.errtok("Expected a register".to_string(), self.peek()),
-        }
+    // .unreq name
+    fn parse_unreq_directive(&mut self) {
         self.increment_position(1);
         self.skip_whitespace();
         match self.peek().value {
-            TokenValue::Comma => {
-                self.increment_position(1);
-                self.skip_whitespace();
-                match self.peek().value {
-                    TokenValue::Register(register_num) => {
-                        offset = Some(RegImmAddr::Register(register_num));
-                    }
-                    TokenValue::Imm(imm) => {
-                        if imm > u16::MAX as u64 {
-                            self.errtok(
-                                "Immediate for offset is too big for LD/ST instruction".to_string(),
-                                self.peek(),
-                            );
-                        }
-                        offset = Some(RegImmAddr::Imm(imm as i16));
-                    }
-                    TokenValue::Char(ch) => {
-                        // I'm leaving this as a quirk for our assembly.
-                        offset = Some(RegImmAddr::Imm(ch as i16));
-                    }
-                    _ => self.errtok("Expected a register or immediate".to_string(), self.peek()),
-                }
-                self.increment_position(1);
+            TokenValue::Label(name) if self.active_aliases.contains_key(&name) => {
+                self.active_aliases.remove(&name);
             }
-            _ => (),
+            _ => self.errtok("Expected an alias defined by .req".to_string(), self.peek()),
         }
+        self.increment_position(1);
         self.skip_whitespace();
         match self.peek().value {
-            TokenValue::RBracket => self.increment_position(1),
-            _ => self.errtok("Expected ']'".to_string(), self.peek()),
+            TokenValue::Newline => self.increment_position(1),
+            _ => self.errtok(
+                format!("Unexpected token {:?}", self.peek().value),
+                self.peek(),
+            ),
         }
+    }
 
-        match offset {
-            Some(offset) => (addr_reg.unwrap(), offset),
-            None => (addr_reg.unwrap(), RegImmAddr::Imm(0)),
+    // The name a register goes by in diagnostics: the .req alias in force at addr, otherwise
+    // the ISA's name for it.
+    pub fn register_name(&self, reg: u8, addr: usize) -> String {
+        let alias = self.register_aliases.iter().find(|alias| {
+            alias.register == reg
+                && alias
+                    .ranges
+                    .iter()
+                    .any(|(start, end)| *start <= addr && addr < *end)
+        });
+        match alias {
+            Some(alias) => alias.name.clone(),
+            None => isa::current().conventional_name(reg),
         }
     }
 
-    fn parse_data_section(&mut self) {
-        // also keep track of label definitions. we don't allow label usages in the data section
+    // .struct Name
+    //     field: .1b|.2b|.4b|.8b [count]
+    //     ...
+    // .ends
+    // defines the constants Name.field (offset of the field) and Name.size
+    fn parse_struct_directive(&mut self) {
+        let directive = self.peek();
+        let mut name: Option<String> = None;
+        self.increment_position(1);
+        self.skip_whitespace();
+        match self.peek().value {
+            TokenValue::Label(label) => name = Some(label),
+            _ => self.errtok("Expected a struct name".to_string(), self.peek()),
+        }
         self.increment_position(1);
+        let name = name.unwrap();
+        let mut offset: u64 = 0;
         while !self.is_at_end() {
             let t = self.peek();
             match t.value.clone() {
-                TokenValue::SectionDirective(_) | TokenValue::Eof => break,
                 TokenValue::Whitespace | TokenValue::Newline => self.increment_position(1),
-                TokenValue::LabelDef(label) => {
+                TokenValue::Directive(Directive::Ends) => {
                     self.increment_position(1);
-                    self.mapping.insert(label, self.data_section_offset);
+                    self.define_constant(format!("{}.size", name), offset, directive);
+                    return;
                 }
-                TokenValue::DataTypeDirective(datatype) => self.parse_datatype_directive(datatype),
+                TokenValue::LabelDef(field) => {
+                    self.increment_position(1);
+                    self.skip_whitespace();
+                    let mut size: u64 = 0;
+                    match self.peek().value {
+                        TokenValue::DataTypeDirective(DataTypeDirective::Byte1) => size = 1,
+                        TokenValue::DataTypeDirective(DataTypeDirective::Byte2) => size = 2,
+                        TokenValue::DataTypeDirective(DataTypeDirective::Byte4) => size = 4,
+                        TokenValue::DataTypeDirective(DataTypeDirective::Byte8) => size = 8,
+                        _ => self.errtok(
+                            "Expected a .1b/.2b/.4b/.8b field size".to_string(),
+                            self.peek(),
+                        ),
+                    }
+                    self.increment_position(1);
+                    self.skip_whitespace();
+                    let mut count: u64 = 1;
+                    if let TokenValue::Imm(imm) = self.peek().value {
+                        count = imm;
+                        self.increment_position(1);
+                        self.skip_whitespace();
+                    }
+                    self.define_constant(format!("{}.{}", name, field), offset, t);
+                    offset += size * count;
+                }
+                TokenValue::SectionDirective(_) | TokenValue::Eof => break,
                 _ => self.errtok(format!("Unexpected token {:?}", t.value), t),
             }
         }
+        self.errtok(format!("Missing .ends for struct \"{}\"", name), directive);
     }
 
     fn parse_datatype_directive(&mut self, datatype: DataTypeDirective) {
@@ -669,8 +1227,11 @@ impl Parser {
                 (TokenValue::Char(ch), DataTypeDirective::Char) => {
                     data.push(Data::Char(ch));
                 }
-                (TokenValue::String(str), DataTypeDirective::String) => {
-                    data.push(Data::String(str));
+                (
+                    TokenValue::String(bytes),
+                    DataTypeDirective::String | DataTypeDirective::Ascii | DataTypeDirective::Asciz,
+                ) => {
+                    data.push(Data::String(bytes));
                 }
                 (_, _) => self.errtok(format!("Unexpected token {:?}", t.value), t),
             }
@@ -700,31 +1261,18 @@ impl Parser {
         debug!("{:?}", data);
         for d in data.into_iter() {
             match d {
-                Data::String(str) => {
-                    self.data_section.append(&mut str.as_bytes().to_vec());
-                    self.data_section.push(0);
-                    self.data_section_offset += str.len() + 1; // +1 for null terminator
-                }
-                Data::Char(ch) => {
-                    self.data_section.push(ch as u8);
-                    self.data_section_offset += 1;
-                }
-                Data::Byte1(byte1) => {
-                    self.data_section.push(byte1);
-                    self.data_section_offset += 1;
-                }
-                Data::Byte2(byte2) => {
-                    self.data_section.append(&mut byte2.to_le_bytes().to_vec());
-                    self.data_section_offset += 2;
-                }
-                Data::Byte4(byte4) => {
-                    self.data_section.append(&mut byte4.to_le_bytes().to_vec());
-                    self.data_section_offset += 4;
-                }
-                Data::Byte8(byte8) => {
-                    self.data_section.append(&mut byte8.to_le_bytes().to_vec());
-                    self.data_section_offset += 8;
+                Data::String(mut bytes) => {
+                    // .ascii is the only string directive without a null terminator
+                    if datatype != DataTypeDirective::Ascii {
+                        bytes.push(0);
+                    }
+                    self.emit_data(&bytes);
                 }
+                Data::Char(ch) => self.emit_data(&[ch as u8]),
+                Data::Byte1(byte1) => self.emit_data(&[byte1]),
+                Data::Byte2(byte2) => self.emit_data(&byte2.to_le_bytes()),
+                Data::Byte4(byte4) => self.emit_data(&byte4.to_le_bytes()),
+                Data::Byte8(byte8) => self.emit_data(&byte8.to_le_bytes()),
             }
         }
         debug!("{:?}", self.data_section);
@@ -733,6 +1281,17 @@ impl Parser {
     fn resolve_labels(&mut self) {
         // resolve labels. if label not the hashmap, we have an error.
         for i in 0..self.instructions.len() {
+            if let (
+                Instruction::Ld(_, RegImmAddr::Unresolved(label, _, _))
+                | Instruction::B(RegImmAddr::Unresolved(label, _, _))
+                | Instruction::BL(RegImmAddr::Unresolved(label, _, _))
+                | Instruction::CBZ(_, RegImmAddr::Unresolved(label, _, _))
+                | Instruction::CBNZ(_, RegImmAddr::Unresolved(label, _, _)),
+                Some(loc),
+            ) = (&self.instructions[i], self.instruction_locs[i])
+            {
+                self.references.entry(label.clone()).or_default().push(loc);
+            }
             match &self.instructions[i] {
                 Instruction::Ld(dst, RegImmAddr::Unresolved(label, pc, const_pool)) => {
                     // since for LD reg, label we need the physical label to be loaded from memory,
@@ -740,15 +1299,20 @@ impl Parser {
                     // leave space for it when it gets resolved.
                     match self.mapping.get(label) {
                         Some(addr) => {
-                            let offset = *const_pool - CONSTANT_POOL_OFFSET;
+                            let offset = *const_pool - self.memory_map.constant_pool.base;
                             self.constant_pool.splice(
                                 (offset)..(offset + 8),
                                 (*addr as u64).to_le_bytes().to_vec(),
                             );
-                            self.instructions[i] = Instruction::Ld(
-                                *dst,
-                                RegImmAddr::Address((offset as isize - *pc as isize) as i16),
-                            )
+                            let offset = self.offset(
+                                &self.instructions[i],
+                                self.instruction_locs[i],
+                                &format!("the constant pool entry for \"{}\"", label),
+                                *const_pool,
+                                *pc,
+                            );
+                            self.instructions[i] =
+                                Instruction::Ld(*dst, RegImmAddr::Address(offset))
                         }
                         None => self.errmsg(format!("Label \"{}\" is undefined", label)),
                     }
@@ -756,9 +1320,29 @@ impl Parser {
                 Instruction::B(RegImmAddr::Unresolved(label, pc, _)) => {
                     match self.mapping.get(label) {
                         Some(addr) => {
-                            self.instructions[i] = Instruction::B(RegImmAddr::Address(
-                                (*addr as isize - *pc as isize) as i16,
-                            ))
+                            let offset = self.offset(
+                                &self.instructions[i],
+                                self.instruction_locs[i],
+                                &format!("\"{}\"", label),
+                                *addr,
+                                *pc,
+                            );
+                            self.instructions[i] = Instruction::B(RegImmAddr::Address(offset))
+                        }
+                        None => self.errmsg(format!("Label \"{}\" is undefined", label)),
+                    }
+                }
+                Instruction::BL(RegImmAddr::Unresolved(label, pc, _)) => {
+                    match self.mapping.get(label) {
+                        Some(addr) => {
+                            let offset = self.offset(
+                                &self.instructions[i],
+                                self.instruction_locs[i],
+                                &format!("\"{}\"", label),
+                                *addr,
+                                *pc,
+                            );
+                            self.instructions[i] = Instruction::BL(RegImmAddr::Address(offset))
                         }
                         None => self.errmsg(format!("Label \"{}\" is undefined", label)),
                     }
@@ -766,10 +1350,15 @@ impl Parser {
                 Instruction::CBZ(reg, RegImmAddr::Unresolved(label, pc, _)) => {
                     match self.mapping.get(label) {
                         Some(addr) => {
-                            self.instructions[i] = Instruction::CBZ(
-                                *reg,
-                                RegImmAddr::Address((*addr as isize - *pc as isize) as i16),
-                            )
+                            let offset = self.offset(
+                                &self.instructions[i],
+                                self.instruction_locs[i],
+                                &format!("\"{}\"", label),
+                                *addr,
+                                *pc,
+                            );
+                            self.instructions[i] =
+                                Instruction::CBZ(*reg, RegImmAddr::Address(offset))
                         }
                         None => self.errmsg(format!("Label \"{}\" is undefined", label)),
                     }
@@ -777,10 +1366,15 @@ impl Parser {
                 Instruction::CBNZ(reg, RegImmAddr::Unresolved(label, pc, _)) => {
                     match self.mapping.get(label) {
                         Some(addr) => {
-                            self.instructions[i] = Instruction::CBNZ(
-                                *reg,
-                                RegImmAddr::Address((*addr as isize - *pc as isize) as i16),
-                            )
+                            let offset = self.offset(
+                                &self.instructions[i],
+                                self.instruction_locs[i],
+                                &format!("\"{}\"", label),
+                                *addr,
+                                *pc,
+                            );
+                            self.instructions[i] =
+                                Instruction::CBNZ(*reg, RegImmAddr::Address(offset))
                         }
                         None => self.errmsg(format!("Label \"{}\" is undefined", label)),
                     }
@@ -789,4 +1383,96 @@ impl Parser {
             }
         }
     }
+
+    // The PC-relative offset from pc to addr for the instruction at loc, which has to reach it.
+    // target says what's at addr.
+    fn offset(
+        &self,
+        instruction: &Instruction,
+        loc: Option<Loc>,
+        target: &str,
+        addr: usize,
+        pc: usize,
+    ) -> i16 {
+        let isa = isa::current();
+        let offset = addr as i64 - pc as i64;
+        let reach = isa
+            .reach(instruction)
+            .unwrap_or(i16::MIN as i64..i16::MAX as i64 + 1);
+        if reach.contains(&offset) {
+            if let Ok(offset) = i16::try_from(offset) {
+                return offset;
+            }
+        }
+        let mnemonic = isa
+            .encoding_for(instruction)
+            .map_or("Instruction".to_string(), |encoding| {
+                encoding.mnemonic.clone()
+            });
+        let at = match loc {
+            Some(loc) => format!(" at {}:{}", loc.line, loc.col),
+            None => String::new(),
+        };
+        error!(
+            "{}{} can't reach {} at {:#06x}, {} bytes away (it reaches {}..{})",
+            mnemonic,
+            at,
+            target,
+            addr,
+            offset,
+            reach.start,
+            reach.end - 1
+        );
+        std::process::exit(exitcode::SOURCE);
+    }
+
+    // Once a program uses the stack, SP belongs to PUSH and POP.
+    fn check_stack_writes(&self) {
+        let isa = isa::current();
+        let stack = match isa.registers.stack {
+            Some(stack) if self.stack_used => stack,
+            _ => return,
+        };
+        for (loc, addr) in &self.stack_writes {
+            warn!(
+                "Write to {}, the stack pointer, outside PUSH/POP at {}:{}",
+                self.register_name(stack, *addr),
+                loc.line,
+                loc.col
+            );
+        }
+    }
+
+    fn check_section_sizes(&self) {
+        let map = &self.memory_map;
+        let data = match self.stack_size {
+            0 => "Data section".to_string(),
+            size => format!("Data section (below a {} byte stack)", size),
+        };
+        let sections = [
+            (
+                "Constant pool",
+                self.constant_pool_offset,
+                map.constant_pool.end(),
+            ),
+            (
+                data.as_str(),
+                map.data.base + self.data_section.len(),
+                map.data.end() - self.stack_size,
+            ),
+            (
+                "Text section",
+                map.text.base + self.instructions.len() * 4,
+                map.text.end(),
+            ),
+        ];
+        for (name, end, limit) in sections {
+            if end > limit {
+                self.errmsg(format!(
+                    "{} overflows its region: ends at {:#06x}, limit is {:#06x}",
+                    name, end, limit
+                ));
+            }
+        }
+    }
 }
@@ -0,0 +1,274 @@
+use crate::disasm::format_instruction;
+use crate::isa;
+use crate::parser::{AluOp, Instruction, Parser, RegImmAddr};
+use crate::rewrite::{self, Slot};
+use log::{info, warn};
+use std::collections::HashMap;
+
+// -O: peephole rewrites of the assembled text section, repeated until none applies. Every
+// rewrite is logged.
+pub fn optimize(parser: &mut Parser) {
+    if parser.instruction_locs.contains(&None) {
+        warn!("-O leaves alone a text section placed with .org");
+        return;
+    }
+    let (blocks, _) = rewrite::blocks(parser);
+    let mut peephole = Peephole {
+        parser,
+        slots: rewrite::slots(parser, &blocks),
+    };
+    let before = peephole.slots.len();
+    while peephole.remove_noops()
+        || peephole.fold_adds()
+        || peephole.thread_branches()
+        || peephole.remove_branches_to_next()
+        || peephole.remove_unreachable()
+    {}
+    let slots = peephole.slots;
+    let after = slots.len();
+
+    // a removed instruction's address now points at the next one that's left
+    let mut position = Vec::new();
+    for original in 0..=parser.instructions.len() {
+        position.push(
+            slots
+                .iter()
+                .position(|slot| slot.original.is_some_and(|at| at >= original))
+                .unwrap_or(after),
+        );
+    }
+    rewrite::install(parser, &slots, &position);
+    info!("-O: {} of {} instructions left", after, before);
+}
+
+struct Peephole<'a> {
+    parser: &'a Parser,
+    slots: Vec<Slot>,
+}
+
+impl Peephole<'_> {
+    // The instruction as it was written, with its labels, and where.
+    fn describe(&self, slot: &Slot) -> String {
+        let parser = self.parser;
+        let base = parser.memory_map.text.base;
+        let pc = base + slot.original.unwrap_or(0) * 4;
+        let labels: HashMap<usize, &String> = parser
+            .mapping
+            .iter()
+            .map(|(name, addr)| (*addr, name))
+            .collect();
+        let name = |addr: usize| match labels.get(&addr) {
+            Some(label) => label.to_string(),
+            None => format!("{:#06x}", addr),
+        };
+        let registers = |reg: u8| parser.register_name(reg, pc);
+        let text = format_instruction(&slot.instruction, pc, &name, &registers);
+        match slot.loc {
+            Some(loc) => format!("{} at {}:{}", text, loc.line, loc.col),
+            None => text,
+        }
+    }
+
+    // Where the instruction at an original index, or the one after it if it's gone, is now.
+    fn position(&self, original: usize) -> usize {
+        self.slots
+            .iter()
+            .position(|slot| slot.original.is_some_and(|at| at >= original))
+            .unwrap_or(self.slots.len())
+    }
+
+    // Whether anything can get to the slot other than by running into it: a label or a branch.
+    fn entered(&self, at: usize) -> bool {
+        let original = match self.slots[at].original {
+            Some(original) => original,
+            None => return false,
+        };
+        let base = self.parser.memory_map.text.base;
+        let previous = match at {
+            0 => 0,
+            at => self.slots[at - 1]
+                .original
+                .map_or(0, |original| original + 1),
+        };
+        // the addresses of removed instructions just before it lead here too
+        let addresses = previous..=original;
+        self.parser
+            .mapping
+            .values()
+            .any(|addr| *addr >= base && addresses.contains(&((addr - base) / 4)))
+            || self.slots.iter().any(|slot| {
+                slot.target
+                    .is_some_and(|target| addresses.contains(&target))
+            })
+    }
+
+    fn remove_noops(&mut self) -> bool {
+        let zero = isa::current().registers.zero;
+        let noop = |instruction: &Instruction| match instruction {
+            Instruction::Alu(op, rd, rn, RegImmAddr::Imm(imm))
+                if rd == rn
+                    && matches!(
+                        (op, imm),
+                        (
+                            AluOp::Add
+                                | AluOp::Sub
+                                | AluOp::Orr
+                                | AluOp::Eor
+                                | AluOp::Lsl
+                                | AluOp::Lsr
+                                | AluOp::Asr,
+                            0
+                        ) | (AluOp::Mul | AluOp::Div, 1)
+                    ) =>
+            {
+                true
+            }
+            // a write to the zero register is lost, but a division by zero still stops the CPU
+            Instruction::Alu(op, rd, _, src) => {
+                Some(*rd) == zero
+                    && (!matches!(op, AluOp::Div | AluOp::Mod)
+                        || matches!(src, RegImmAddr::Imm(imm) if *imm != 0))
+            }
+            Instruction::Ld(rd, RegImmAddr::Register(rn)) => rd == rn || Some(*rd) == zero,
+            Instruction::Neg(rd, _) => Some(*rd) == zero,
+            _ => false,
+        };
+        match self.slots.iter().position(|slot| noop(&slot.instruction)) {
+            Some(at) => {
+                info!(
+                    "-O: removed {}, which does nothing",
+                    self.describe(&self.slots[at])
+                );
+                self.slots.remove(at);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // ADD/SUB rd, rn, a followed by ADD/SUB rd, rd, b is ADD/SUB rd, rn, a +/- b.
+    fn fold_adds(&mut self) -> bool {
+        let signed = |instruction: &Instruction| match instruction {
+            Instruction::Alu(AluOp::Add, rd, rn, RegImmAddr::Imm(imm)) => {
+                Some((*rd, *rn, *imm as i64))
+            }
+            Instruction::Alu(AluOp::Sub, rd, rn, RegImmAddr::Imm(imm)) => {
+                Some((*rd, *rn, -(*imm as i64)))
+            }
+            _ => None,
+        };
+        for at in 1..self.slots.len() {
+            let (first, second) = (&self.slots[at - 1], &self.slots[at]);
+            let folded = match (signed(&first.instruction), signed(&second.instruction)) {
+                (Some((rd, rn, a)), Some((rd2, rn2, b))) if rd2 == rd && rn2 == rd => {
+                    let sum = a + b;
+                    let op = if sum < 0 { AluOp::Sub } else { AluOp::Add };
+                    i16::try_from(sum.unsigned_abs())
+                        .ok()
+                        .map(|imm| Instruction::Alu(op, rd, rn, RegImmAddr::Imm(imm)))
+                }
+                _ => None,
+            };
+            let folded = match folded {
+                Some(folded) if !self.entered(at) && isa::current().fits(&folded) => folded,
+                _ => continue,
+            };
+            info!(
+                "-O: folded {} and {} into one instruction",
+                self.describe(first),
+                self.describe(second)
+            );
+            self.slots[at - 1].instruction = folded;
+            self.slots.remove(at);
+            return true;
+        }
+        false
+    }
+
+    // A branch to a B goes straight to where the B goes.
+    fn thread_branches(&mut self) -> bool {
+        for at in 0..self.slots.len() {
+            let target = match self.slots[at].target {
+                Some(target) => self.position(target),
+                None => continue,
+            };
+            let through = match self.slots.get(target) {
+                Some(
+                    slot @ Slot {
+                        instruction: Instruction::B(_),
+                        target: Some(next),
+                        ..
+                    },
+                ) if !self.loops(target) => (slot, *next),
+                _ => continue,
+            };
+            info!(
+                "-O: threaded {} through {}",
+                self.describe(&self.slots[at]),
+                self.describe(through.0)
+            );
+            self.slots[at].target = Some(through.1);
+            return true;
+        }
+        false
+    }
+
+    // Whether following the B at a slot from B to B comes back around, like "a: B b" "b: B a".
+    fn loops(&self, at: usize) -> bool {
+        let mut seen = vec![at];
+        let mut at = at;
+        while let Some(Slot {
+            instruction: Instruction::B(_),
+            target: Some(next),
+            ..
+        }) = self.slots.get(at)
+        {
+            at = self.position(*next);
+            if seen.contains(&at) {
+                return true;
+            }
+            seen.push(at);
+        }
+        false
+    }
+
+    fn remove_branches_to_next(&mut self) -> bool {
+        for at in 0..self.slots.len() {
+            let removable = matches!(
+                self.slots[at].instruction,
+                Instruction::B(_) | Instruction::CBZ(..) | Instruction::CBNZ(..)
+            );
+            match self.slots[at].target {
+                Some(target) if removable && self.position(target) == at + 1 => {
+                    info!(
+                        "-O: removed {}, which branches to the next instruction",
+                        self.describe(&self.slots[at])
+                    );
+                    self.slots.remove(at);
+                    return true;
+                }
+                _ => (),
+            }
+        }
+        false
+    }
+
+    // Nothing runs the instructions after HALT, B or BR until a label or branch target.
+    fn remove_unreachable(&mut self) -> bool {
+        for at in 1..self.slots.len() {
+            let after_jump = matches!(
+                self.slots[at - 1].instruction,
+                Instruction::Halt | Instruction::B(_) | Instruction::BR(_)
+            );
+            if after_jump && !self.entered(at) {
+                info!(
+                    "-O: removed {}, which can't be reached",
+                    self.describe(&self.slots[at])
+                );
+                self.slots.remove(at);
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,404 @@
+use crate::exitcode;
+use crate::isa::{self, Operation};
+use crate::parser::{Instruction, Parser};
+use crate::rewrite::{self, Slot};
+use clap::ValueEnum;
+use log::{error, info, warn};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Forwarding {
+    /// Operands are read from the register file, after the producer's write back
+    None,
+    /// Results are forwarded to the execute stage, loads as soon as they leave memory
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HazardFix {
+    /// Only warn about the hazards
+    Report,
+    /// Insert NOPs in front of the instructions that would read too early
+    Nop,
+    /// Move independent instructions into the gaps first, then insert NOPs
+    Reorder,
+}
+
+// An in-order pipeline of IF, ID, EX, ..., MEM, WB. Registers are read in ID, or in EX with
+// forwarding, and written in the first half of WB, so a read in the same cycle sees the value.
+#[derive(Debug, Clone, Copy)]
+pub struct Pipeline {
+    pub stages: usize,
+    pub forwarding: Forwarding,
+}
+
+impl Pipeline {
+    // How many instructions after the producer its result can be read without stalling.
+    fn distance(&self, producer: &Instruction) -> usize {
+        let needed = match self.forwarding {
+            Forwarding::None => self.stages - 2,
+            // loads have their result at the end of MEM, everything else at the end of EX
+            Forwarding::Full if is_load(producer) => self.stages - 3,
+            Forwarding::Full => 1,
+        };
+        needed.max(1)
+    }
+}
+
+fn is_load(instruction: &Instruction) -> bool {
+    matches!(
+        isa::operation(instruction),
+        Operation::Load(..) | Operation::LoadConstant
+    )
+}
+
+fn is_memory(instruction: &Instruction) -> bool {
+    matches!(
+        isa::operation(instruction),
+        Operation::Load(..) | Operation::LoadConstant | Operation::Store(_)
+    )
+}
+
+// a read that comes too soon after the write it depends on
+#[derive(Debug)]
+pub struct Hazard {
+    pub producer: usize,
+    pub consumer: usize,
+    pub register: u8,
+    pub stalls: usize,
+}
+
+// The program as the instructions are moved around and NOPs go in.
+struct Program {
+    slots: Vec<Slot>,
+    block_count: usize,
+}
+
+impl Program {
+    // Where a block starts now, including any NOPs in front of its first instruction.
+    fn block_start(&self, block: usize) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.block == block)
+    }
+
+    fn anchor(&self, original: usize, blocks: &[usize]) -> usize {
+        self.block_start(blocks[original]).unwrap()
+    }
+
+    fn predecessors(&self, blocks: &[usize]) -> Vec<Vec<usize>> {
+        let mut predecessors = vec![Vec::new(); self.slots.len()];
+        for (at, slot) in self.slots.iter().enumerate() {
+            let falls_through = !matches!(
+                slot.instruction,
+                Instruction::Halt | Instruction::B(_) | Instruction::BR(_)
+            );
+            if falls_through && at + 1 < self.slots.len() {
+                predecessors[at + 1].push(at);
+            }
+            if let Some(target) = slot.target {
+                predecessors[self.anchor(target, blocks)].push(at);
+            }
+        }
+        predecessors
+    }
+
+    // Every hazard, found by walking back along each path into every instruction.
+    fn hazards(&self, pipeline: &Pipeline, blocks: &[usize]) -> Vec<Hazard> {
+        let zero = isa::current().registers.zero;
+        let predecessors = self.predecessors(blocks);
+        let window = pipeline.stages;
+        let mut hazards: Vec<Hazard> = Vec::new();
+        for (consumer, slot) in self.slots.iter().enumerate() {
+            let reads: Vec<u8> = isa::read(&slot.instruction)
+                .into_iter()
+                .filter(|reg| Some(*reg) != zero)
+                .collect();
+            // (instruction, distance, registers not yet written nearer the consumer)
+            let mut paths: Vec<(usize, usize, Vec<u8>)> = predecessors[consumer]
+                .iter()
+                .map(|at| (*at, 1, reads.clone()))
+                .collect();
+            let mut seen: HashSet<(usize, usize)> = HashSet::new();
+            while let Some((at, distance, pending)) = paths.pop() {
+                if distance >= window || pending.is_empty() || !seen.insert((at, distance)) {
+                    continue;
+                }
+                let producer = &self.slots[at].instruction;
+                let written = isa::written(producer);
+                let needed = pipeline.distance(producer);
+                for reg in pending.iter().filter(|reg| written.contains(reg)) {
+                    if distance < needed
+                        && !hazards.iter().any(|hazard| {
+                            (hazard.producer, hazard.consumer, hazard.register)
+                                == (at, consumer, *reg)
+                        })
+                    {
+                        hazards.push(Hazard {
+                            producer: at,
+                            consumer,
+                            register: *reg,
+                            stalls: needed - distance,
+                        });
+                    }
+                }
+                let pending: Vec<u8> = pending
+                    .into_iter()
+                    .filter(|reg| !written.contains(reg))
+                    .collect();
+                for before in &predecessors[at] {
+                    paths.push((*before, distance + 1, pending.clone()));
+                }
+            }
+        }
+        hazards.sort_by_key(|hazard| (hazard.consumer, hazard.producer));
+        hazards
+    }
+
+    // Reorders each block's instructions greedily: the next one is the one, of those whose
+    // dependencies have all been placed, that waits least on what's just been placed, the end of
+    // the block before included when it runs into this one. Ties go to the one the rest of the
+    // block, the instruction that ends it included, has the longest chain of reads behind, then
+    // to the original order. The instruction that ends a block stays at its end.
+    fn reorder(&mut self, pipeline: &Pipeline) -> usize {
+        let mut moved = 0;
+        let mut slots = std::mem::take(&mut self.slots).into_iter().peekable();
+        let mut reordered: Vec<Slot> = Vec::new();
+        for block in 0..self.block_count {
+            let mut pending: Vec<Slot> = Vec::new();
+            while let Some(slot) = slots.next_if(|slot| slot.block == block) {
+                pending.push(slot);
+            }
+            let last = match pending.last() {
+                Some(slot) if ends_block(&slot.instruction) => pending.pop(),
+                _ => None,
+            };
+            let mut heights = vec![0usize; pending.len()];
+            for at in (0..pending.len()).rev() {
+                let producer = &pending[at].instruction;
+                heights[at] = pending[at + 1..]
+                    .iter()
+                    .zip(heights[at + 1..].iter().copied())
+                    .chain(last.iter().map(|slot| (slot, 0)))
+                    .filter(|(consumer, _)| reads_from(&consumer.instruction, producer))
+                    .map(|(_, height)| pipeline.distance(producer) + height)
+                    .max()
+                    .unwrap_or(0);
+            }
+            let start = match reordered.last() {
+                Some(slot)
+                    if !matches!(
+                        slot.instruction,
+                        Instruction::Halt | Instruction::B(_) | Instruction::BR(_)
+                    ) =>
+                {
+                    0
+                }
+                _ => reordered.len(),
+            };
+            while !pending.is_empty() {
+                let ready: Vec<usize> = (0..pending.len())
+                    .filter(|candidate| {
+                        pending[..*candidate].iter().all(|earlier| {
+                            !depends(&pending[*candidate].instruction, &earlier.instruction)
+                        })
+                    })
+                    .collect();
+                let stalls = |candidate: usize| -> usize {
+                    let placed = &reordered[start..];
+                    placed
+                        .iter()
+                        .rev()
+                        .enumerate()
+                        .map(|(back, producer)| {
+                            match reads_from(&pending[candidate].instruction, &producer.instruction)
+                            {
+                                true => pipeline
+                                    .distance(&producer.instruction)
+                                    .saturating_sub(back + 1),
+                                false => 0,
+                            }
+                        })
+                        .max()
+                        .unwrap_or(0)
+                };
+                let choice = ready
+                    .iter()
+                    .copied()
+                    .min_by_key(|candidate| {
+                        (
+                            stalls(*candidate),
+                            std::cmp::Reverse(heights[*candidate]),
+                            *candidate,
+                        )
+                    })
+                    .unwrap();
+                if choice != 0 {
+                    moved += 1;
+                }
+                heights.remove(choice);
+                reordered.push(pending.remove(choice));
+            }
+            reordered.extend(last);
+        }
+        self.slots = reordered;
+        moved
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Halt
+            | Instruction::B(_)
+            | Instruction::BL(_)
+            | Instruction::CBZ(..)
+            | Instruction::CBNZ(..)
+            | Instruction::BR(_)
+    )
+}
+
+// Whether later reads a register earlier writes.
+fn reads_from(later: &Instruction, earlier: &Instruction) -> bool {
+    let writes = isa::written(earlier);
+    isa::read(later).iter().any(|reg| writes.contains(reg))
+}
+
+// Whether later has to stay after earlier: it reads what earlier writes, writes what earlier
+// reads or writes, or they both touch memory and one of them stores.
+fn depends(later: &Instruction, earlier: &Instruction) -> bool {
+    let (later_reads, later_writes) = (isa::read(later), isa::written(later));
+    let (earlier_reads, earlier_writes) = (isa::read(earlier), isa::written(earlier));
+    let overlaps = |a: &[u8], b: &[u8]| a.iter().any(|reg| b.contains(reg));
+    let stores = |instruction: &Instruction| matches!(instruction, Instruction::St(..));
+    overlaps(&later_reads, &earlier_writes)
+        || overlaps(&later_writes, &earlier_reads)
+        || overlaps(&later_writes, &earlier_writes)
+        || (is_memory(later) && is_memory(earlier) && (stores(later) || stores(earlier)))
+}
+
+fn program(parser: &Parser, blocks: &[usize], block_count: usize) -> Program {
+    Program {
+        slots: rewrite::slots(parser, blocks),
+        block_count,
+    }
+}
+
+// Finds the hazards in the assembled program for the pipeline.
+pub fn hazards(parser: &Parser, pipeline: &Pipeline) -> Vec<Hazard> {
+    let (blocks, block_count) = rewrite::blocks(parser);
+    program(parser, &blocks, block_count).hazards(pipeline, &blocks)
+}
+
+pub fn report(parser: &Parser, hazards: &[Hazard]) {
+    let base = parser.memory_map.text.base;
+    let loc = |index: usize| match parser.instruction_locs[index] {
+        Some(loc) => format!("{}:{}", loc.line, loc.col),
+        None => format!("{:#06x}", base + index * 4),
+    };
+    let mnemonic = |index: usize| match isa::current().encoding_for(&parser.instructions[index]) {
+        Some(encoding) => encoding.mnemonic.clone(),
+        None => format!("{:?}", parser.instructions[index]),
+    };
+    for hazard in hazards {
+        let kind = match is_load(&parser.instructions[hazard.producer]) {
+            true => "Load-use",
+            false => "RAW",
+        };
+        warn!(
+            "{} hazard on {}: {} at {} reads what {} at {} writes, {} stall cycle{}",
+            kind,
+            parser.register_name(hazard.register, base + hazard.consumer * 4),
+            mnemonic(hazard.consumer),
+            loc(hazard.consumer),
+            mnemonic(hazard.producer),
+            loc(hazard.producer),
+            hazard.stalls,
+            if hazard.stalls == 1 { "" } else { "s" }
+        );
+    }
+}
+
+// Removes every hazard by reordering (if asked to) and then inserting NOPs, and puts the result
+// back into the parser with branch offsets, text labels and constant pool addresses resolved
+// again.
+pub fn fix(parser: &mut Parser, pipeline: &Pipeline, fix: HazardFix) {
+    let nop = match isa::nop() {
+        Some(nop) => nop,
+        None => {
+            error!("The ISA has no zero register to make NOPs from");
+            std::process::exit(exitcode::USAGE);
+        }
+    };
+    if parser.instruction_locs.contains(&None) {
+        error!("Can't move instructions in a text section placed with .org");
+        std::process::exit(exitcode::SOURCE);
+    }
+    let (blocks, block_count) = rewrite::blocks(parser);
+    let mut program = program(parser, &blocks, block_count);
+    let moved = match fix {
+        HazardFix::Reorder => program.reorder(pipeline),
+        _ => 0,
+    };
+    // one NOP at a time, in front of the first instruction that still has to wait
+    let stalls = |program: &Program, consumer: usize| -> usize {
+        program
+            .hazards(pipeline, &blocks)
+            .iter()
+            .filter(|hazard| hazard.consumer == consumer)
+            .map(|hazard| hazard.stalls)
+            .max()
+            .unwrap_or(0)
+    };
+    let mut inserted = 0;
+    while let Some(hazard) = program.hazards(pipeline, &blocks).first() {
+        let at = hazard.consumer;
+        let nop = |slot: &Slot| Slot {
+            instruction: nop.clone(),
+            loc: slot.loc,
+            original: None,
+            block: slot.block,
+            target: None,
+        };
+        inserted += 1;
+        // a hazard from the instruction before a block is fixed there when it can be, so that
+        // the branches into the block don't run the NOP too
+        if at > 0 && program.slots[at - 1].block != program.slots[at].block {
+            let before = stalls(&program, at);
+            program.slots.insert(at, nop(&program.slots[at - 1]));
+            if stalls(&program, at + 1) < before {
+                continue;
+            }
+            program.slots.remove(at);
+        }
+        program.slots.insert(at, nop(&program.slots[at]));
+    }
+
+    let old_len = parser.instructions.len();
+    if program.slots.len() * 4 > parser.memory_map.text.size {
+        error!(
+            "Text section ({} bytes with {} NOPs for the pipeline) doesn't fit in {} bytes",
+            program.slots.len() * 4,
+            inserted,
+            parser.memory_map.text.size
+        );
+        std::process::exit(exitcode::SOURCE);
+    }
+    // where each original instruction's address now points: the start of its block for the
+    // first instruction of a block, so branches and labels take in the NOPs in front of it
+    let mut position = vec![0usize; old_len + 1];
+    for (at, slot) in program.slots.iter().enumerate() {
+        if let Some(original) = slot.original {
+            position[original] = at;
+        }
+    }
+    for original in 0..old_len {
+        if original == 0 || blocks[original] != blocks[original - 1] {
+            position[original] = program.anchor(original, &blocks);
+        }
+    }
+    position[old_len] = program.slots.len();
+    rewrite::install(parser, &program.slots, &position);
+    info!(
+        "Moved {} instructions and inserted {} NOPs for a {}-stage pipeline",
+        moved, inserted, pipeline.stages
+    );
+}
@@ -0,0 +1,189 @@
+use crate::cfg::Cfg;
+use crate::exitcode;
+use crate::isa;
+use crate::parser::{Instruction, Parser, RegImmAddr};
+use crate::token::Loc;
+use log::error;
+use std::collections::HashSet;
+
+// An instruction of the text section while passes move, add and drop instructions, with its
+// branch target kept by original index until the offsets are resolved again.
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub instruction: Instruction,
+    pub loc: Option<Loc>,
+    pub original: Option<usize>, // None for an added instruction
+    pub block: usize,
+    pub target: Option<usize>, // original index of a branch target inside the text section
+}
+
+// Splits the text section into the blocks instructions may move within: the CFG's blocks, also
+// split at every label so that nothing moves past one. Returns the block of each instruction
+// and the number of blocks.
+pub fn blocks(parser: &Parser) -> (Vec<usize>, usize) {
+    let base = parser.memory_map.text.base;
+    let graph = Cfg::new(&parser.instructions, base);
+    let labelled: HashSet<usize> = parser
+        .mapping
+        .values()
+        .filter(|addr| **addr >= base)
+        .map(|addr| (addr - base) / 4)
+        .collect();
+    let mut block_of = Vec::new();
+    let mut block = 0;
+    for index in 0..parser.instructions.len() {
+        let starts_block = graph.blocks.iter().any(|block| block.start == index);
+        if index > 0 && (starts_block || labelled.contains(&index)) {
+            block += 1;
+        }
+        block_of.push(block);
+    }
+    (block_of, block + 1)
+}
+
+pub fn slots(parser: &Parser, blocks: &[usize]) -> Vec<Slot> {
+    parser
+        .instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| {
+            let target = match instruction {
+                Instruction::B(RegImmAddr::Address(offset))
+                | Instruction::BL(RegImmAddr::Address(offset))
+                | Instruction::CBZ(_, RegImmAddr::Address(offset))
+                | Instruction::CBNZ(_, RegImmAddr::Address(offset)) => {
+                    let target = ((index * 4) as isize + *offset as isize) / 4;
+                    (0..parser.instructions.len() as isize)
+                        .contains(&target)
+                        .then_some(target as usize)
+                }
+                _ => None,
+            };
+            Slot {
+                instruction: instruction.clone(),
+                loc: parser.instruction_locs[index],
+                original: Some(index),
+                block: blocks[index],
+                target,
+            }
+        })
+        .collect()
+}
+
+// The branch or constant pool load with its offset replaced.
+fn with_offset(instruction: &Instruction, offset: i16) -> Instruction {
+    let offset = RegImmAddr::Address(offset);
+    match instruction {
+        Instruction::B(_) => Instruction::B(offset),
+        Instruction::BL(_) => Instruction::BL(offset),
+        Instruction::CBZ(rn, _) => Instruction::CBZ(*rn, offset),
+        Instruction::CBNZ(rn, _) => Instruction::CBNZ(*rn, offset),
+        Instruction::Ld(rd, _) => Instruction::Ld(*rd, offset),
+        instruction => instruction.clone(),
+    }
+}
+
+// Rewriting moved what the branch or constant pool load in the slot refers to out of its reach.
+fn out_of_reach(slot: &Slot, target: &str, offset: i64) -> ! {
+    let isa = isa::current();
+    let mnemonic = isa
+        .encoding_for(&slot.instruction)
+        .map_or("Instruction".to_string(), |encoding| {
+            encoding.mnemonic.clone()
+        });
+    let at = match slot.loc {
+        Some(loc) => format!(" at {}:{}", loc.line, loc.col),
+        None => String::new(),
+    };
+    let reach = match isa.reach(&slot.instruction) {
+        Some(reach) => format!(" (it reaches {}..{})", reach.start, reach.end - 1),
+        None => String::new(),
+    };
+    error!(
+        "Rewriting the text section puts the {} of {}{} out of reach, {} bytes away{}",
+        target, mnemonic, at, offset, reach
+    );
+    std::process::exit(exitcode::SOURCE);
+}
+
+// Puts the rewritten instructions back into the parser. position holds where the address of each
+// original instruction, and of the end of the text, points now. Branch offsets, constant pool
+// offsets, text labels, the text addresses held in the constant pool and the ranges of register
+// aliases are all resolved again.
+pub fn install(parser: &mut Parser, slots: &[Slot], position: &[usize]) {
+    let base = parser.memory_map.text.base;
+    let old_len = parser.instructions.len();
+    let new_addr = |addr: usize| -> usize {
+        match addr >= base && addr <= base + old_len * 4 {
+            true => base + position[(addr - base) / 4] * 4,
+            false => addr,
+        }
+    };
+
+    let mut instructions = Vec::new();
+    let mut locs = Vec::new();
+    for (at, slot) in slots.iter().enumerate() {
+        let pc = base + at * 4;
+        let old_pc = slot
+            .original
+            .map(|original| base + original * 4)
+            .unwrap_or(pc);
+        // the new offset of a branch or constant pool load, and what it reaches
+        let moved = match &slot.instruction {
+            Instruction::B(RegImmAddr::Address(offset))
+            | Instruction::BL(RegImmAddr::Address(offset))
+            | Instruction::CBZ(_, RegImmAddr::Address(offset))
+            | Instruction::CBNZ(_, RegImmAddr::Address(offset)) => {
+                let target = match slot.target {
+                    Some(target) => base + position[target] * 4,
+                    None => new_addr((old_pc as isize + *offset as isize) as usize),
+                };
+                Some((target as i64 - pc as i64, "branch target"))
+            }
+            // the constant pool doesn't move
+            Instruction::Ld(_, RegImmAddr::Address(offset)) => Some((
+                old_pc as i64 + *offset as i64 - pc as i64,
+                "constant pool entry",
+            )),
+            _ => None,
+        };
+        let instruction = match moved {
+            Some((offset, target)) => match i16::try_from(offset)
+                .ok()
+                .map(|offset| with_offset(&slot.instruction, offset))
+                .filter(|instruction| isa::current().fits(instruction))
+            {
+                Some(instruction) => instruction,
+                None => out_of_reach(slot, target, offset),
+            },
+            None => slot.instruction.clone(),
+        };
+        instructions.push(instruction);
+        locs.push(slot.loc);
+    }
+    parser.instructions = instructions;
+    parser.instruction_locs = locs;
+
+    let text = parser.memory_map.text;
+    for addr in parser.mapping.values_mut() {
+        if text.base <= *addr && *addr <= text.end() {
+            *addr = new_addr(*addr);
+        }
+    }
+    let pool_base = parser.memory_map.constant_pool.base;
+    for slot in &parser.constant_slots {
+        if let Some(addr) = slot
+            .label
+            .as_ref()
+            .and_then(|label| parser.mapping.get(label))
+        {
+            let offset = slot.addr - pool_base;
+            parser.constant_pool[offset..offset + 8].copy_from_slice(&(*addr as u64).to_le_bytes());
+        }
+    }
+    for alias in &mut parser.register_aliases {
+        for range in &mut alias.ranges {
+            *range = (new_addr(range.0), new_addr(range.1));
+        }
+    }
+}
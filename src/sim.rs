@@ -0,0 +1,221 @@
+use crate::disasm::{decode_instruction, format_instruction, register_name};
+use crate::isa;
+use crate::memmap::MemoryMap;
+use crate::parser::{AluOp, Instruction, RegImmAddr};
+
+// The data memory of the circuit is 256 bytes, even when the memory map uses less of it.
+const MIN_RAM_SIZE: usize = 0x100;
+
+// An instruction-level model of the CPU: the ISA's 64-bit registers, a RAM holding the constant
+// pool and the data section, and a ROM holding the text section.
+pub struct Machine {
+    pub registers: Vec<u64>,
+    pub pc: usize,
+    pub ram: Vec<u8>,
+    rom: Vec<u8>,
+    text_base: usize,
+    pub steps: usize,
+}
+
+impl Machine {
+    pub fn new(memory_map: &MemoryMap, mut ram: Vec<u8>, rom: Vec<u8>) -> Self {
+        if ram.len() < MIN_RAM_SIZE {
+            ram.resize(MIN_RAM_SIZE, 0);
+        }
+        Self {
+            registers: vec![0; isa::current().registers.count as usize],
+            pc: memory_map.text.base,
+            ram,
+            rom,
+            text_base: memory_map.text.base,
+            steps: 0,
+        }
+    }
+
+    pub fn fetch(&self) -> Result<Instruction, String> {
+        let offset = self.pc.wrapping_sub(self.text_base);
+        let bytes = match self.rom.get(offset..offset + 4) {
+            Some(bytes) if self.pc.is_multiple_of(4) => bytes,
+            _ => return Err(format!("PC {:#06x} is outside the text section", self.pc)),
+        };
+        let word = u32::from_le_bytes(bytes.try_into().unwrap());
+        decode_instruction(word)
+            .ok_or_else(|| format!("Undefined instruction {:#010x} at {:#06x}", word, self.pc))
+    }
+
+    // Runs until HALT, returning an error if the program faults or doesn't halt in max_steps.
+    // With trace set, every instruction is printed before it executes.
+    pub fn run(&mut self, max_steps: usize, trace: bool) -> Result<(), String> {
+        loop {
+            let instruction = self.fetch()?;
+            if trace {
+                let text = format_instruction(
+                    &instruction,
+                    self.pc,
+                    &|addr| format!("{:#06x}", addr),
+                    &register_name,
+                );
+                println!("{:04x}  {}", self.pc, text);
+            }
+            if let Instruction::Halt = instruction {
+                return Ok(());
+            }
+            if self.steps == max_steps {
+                return Err(format!("No HALT after {} steps", max_steps));
+            }
+            self.execute(&instruction)?;
+            self.steps += 1;
+        }
+    }
+
+    fn read(&self, reg: u8) -> u64 {
+        self.registers[reg as usize]
+    }
+
+    fn write(&mut self, reg: u8, value: u64) {
+        // writes to the zero register are thrown away
+        if Some(reg) != isa::current().registers.zero {
+            self.registers[reg as usize] = value;
+        }
+    }
+
+    fn operand(&self, operand: &RegImmAddr) -> u64 {
+        match operand {
+            RegImmAddr::Register(reg) => self.read(*reg),
+            RegImmAddr::Imm(imm) | RegImmAddr::Address(imm) => *imm as i64 as u64,
+            RegImmAddr::Unresolved(..) => 0,
+        }
+    }
+
+    fn target(&self, offset: &RegImmAddr) -> usize {
+        (self.pc as i64).wrapping_add(self.operand(offset) as i64) as usize
+    }
+
+    fn memory(&self, addr: u64, num_bytes: u8) -> Result<usize, String> {
+        let addr = addr as usize;
+        match addr.checked_add(num_bytes as usize) {
+            Some(end) if end <= self.ram.len() => Ok(addr),
+            _ => Err(format!(
+                "{}-byte access at {:#x} is outside memory (PC {:#06x})",
+                num_bytes, addr, self.pc
+            )),
+        }
+    }
+
+    fn load(&self, addr: u64, num_bytes: u8, sign_extend: bool) -> Result<u64, String> {
+        let addr = self.memory(addr, num_bytes)?;
+        let mut bytes = [0u8; 8];
+        bytes[..num_bytes as usize].copy_from_slice(&self.ram[addr..addr + num_bytes as usize]);
+        let value = u64::from_le_bytes(bytes);
+        let unused = 64 - num_bytes as u32 * 8;
+        if sign_extend && unused > 0 {
+            Ok((((value << unused) as i64) >> unused) as u64)
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn store(&mut self, addr: u64, num_bytes: u8, value: u64) -> Result<(), String> {
+        let addr = self.memory(addr, num_bytes)?;
+        self.ram[addr..addr + num_bytes as usize]
+            .copy_from_slice(&value.to_le_bytes()[..num_bytes as usize]);
+        Ok(())
+    }
+
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), String> {
+        let mut next_pc = self.pc + 4;
+        match instruction {
+            Instruction::Halt => (),
+            Instruction::Alu(op, rd, rn, src) => {
+                let (lhs, rhs) = (self.read(*rn), self.operand(src));
+                let value = match op {
+                    AluOp::Add => lhs.wrapping_add(rhs),
+                    AluOp::Sub => lhs.wrapping_sub(rhs),
+                    AluOp::Mul => lhs.wrapping_mul(rhs),
+                    AluOp::Div | AluOp::Mod if rhs == 0 => {
+                        return Err(format!("Division by zero at {:#06x}", self.pc))
+                    }
+                    AluOp::Div => (lhs as i64).wrapping_div(rhs as i64) as u64,
+                    AluOp::Mod => (lhs as i64).wrapping_rem(rhs as i64) as u64,
+                    AluOp::Asr => (lhs as i64).wrapping_shr(rhs as u32) as u64,
+                    AluOp::Lsl => lhs.wrapping_shl(rhs as u32),
+                    AluOp::Lsr => lhs.wrapping_shr(rhs as u32),
+                    AluOp::And => lhs & rhs,
+                    AluOp::Orr => lhs | rhs,
+                    AluOp::Eor => lhs ^ rhs,
+                };
+                self.write(*rd, value)
+            }
+            Instruction::Neg(rd, src) => self.write(*rd, self.operand(src).wrapping_neg()),
+            Instruction::Swap(rd, rn) => {
+                let (a, b) = (self.read(*rd), self.read(*rn));
+                self.write(*rd, b);
+                self.write(*rn, a);
+            }
+            Instruction::Ld(rd, offset @ (RegImmAddr::Address(_) | RegImmAddr::Imm(_))) => {
+                // PC-relative load from the constant pool
+                let value = self.load(self.target(offset) as u64, 8, false)?;
+                self.write(*rd, value)
+            }
+            Instruction::Ld(rd, src) => self.write(*rd, self.operand(src)),
+            Instruction::LdMem(num_bytes, sign_extend, rd, rn, offset) => {
+                let addr = self.read(*rn).wrapping_add(self.operand(offset));
+                let value = self.load(addr, *num_bytes, *sign_extend)?;
+                self.write(*rd, value)
+            }
+            Instruction::St(num_bytes, rd, rn, offset) => {
+                let addr = self.read(*rn).wrapping_add(self.operand(offset));
+                self.store(addr, *num_bytes, self.read(*rd))?
+            }
+            Instruction::B(target) => next_pc = self.target(target),
+            Instruction::CBZ(rn, target) => {
+                if self.read(*rn) == 0 {
+                    next_pc = self.target(target)
+                }
+            }
+            Instruction::BL(target) => {
+                if let Some(link) = isa::current().registers.link {
+                    self.write(link, (self.pc + 4) as u64);
+                }
+                next_pc = self.target(target)
+            }
+            Instruction::BR(rn) => next_pc = self.read(*rn) as usize,
+            Instruction::CBNZ(rn, target) => {
+                if self.read(*rn) != 0 {
+                    next_pc = self.target(target)
+                }
+            }
+        }
+        self.pc = next_pc;
+        Ok(())
+    }
+
+    pub fn dump_registers(&self) -> String {
+        let mut text = String::new();
+        for (reg, value) in self.registers.iter().enumerate() {
+            text.push_str(&format!(
+                "{:4} = {:#018x} ({})\n",
+                register_name(reg as u8),
+                value,
+                *value as i64
+            ));
+        }
+        text
+    }
+
+    pub fn dump_ram(&self) -> String {
+        let mut text = String::new();
+        for (i, chunk) in self.ram.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|byte| match byte {
+                    0x20..=0x7e => *byte as char,
+                    _ => '.',
+                })
+                .collect();
+            text.push_str(&format!("{:04x}  {}  {}\n", i * 16, hex.join(" "), ascii));
+        }
+        text
+    }
+}
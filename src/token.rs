@@ -1,4 +1,6 @@
-#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq)]
+use serde::Serialize;
+
+#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub struct Loc {
     pub line: u32,
     pub col: u32,
@@ -10,15 +12,17 @@ pub struct Loc {
 //     }
 // }
 
-#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize)]
 pub enum SectionDirective {
     Data,
     Text,
 }
 
-#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize)]
 pub enum DataTypeDirective {
-    String,
+    String, // same as .asciz
+    Ascii,
+    Asciz,
     Char,
     Byte1,
     Byte2,
@@ -26,47 +30,40 @@ pub enum DataTypeDirective {
     Byte8,
 }
 
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize)]
+pub enum Directive {
+    Incbin,
+    Space,
+    Stack,
+    Req,
+    Unreq,
+    Struct,
+    Ends,
+    Org,
+    Section,
+}
+
 #[derive(Debug, Clone)]
 pub enum CommentType {
     Line,
     MultiLine,
 }
 
-#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize)]
 pub enum TokenValue {
-    Add,
-    Sub,
-    Mul,
-    Div,
-    Mod,
-    Asr,
-    Lsl,
-
-    And,
-    Orr,
-    Neg,
-
-    Swap,
-
-    Ld(u8, bool), // num bytes, sign-extension
-    St(u8),       // num bytes
-
-    B,
-    CBZ,
-    CBNZ,
-
-    Halt,
+    Mnemonic(String), // an instruction from the ISA, in upper case
 
     Register(u8),
     Imm(u64),
-    Char(char),
-    String(String),
+    Char(char),      // always a single byte value (ASCII or an escape sequence)
+    String(Vec<u8>), // raw bytes, escapes already decoded
 
     Label(String),
     LabelDef(String),
 
     SectionDirective(SectionDirective),
     DataTypeDirective(DataTypeDirective),
+    Directive(Directive),
 
     Comma,
     Colon,
@@ -75,11 +72,12 @@ pub enum TokenValue {
 
     Whitespace,
     Newline,
+    Comment(String), // only kept by Lexer::lossless
 
     Eof,
 }
 
-#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+#[derive(Hash, PartialEq, Eq, Debug, Clone, Serialize)]
 pub struct Token {
     pub loc: Loc,
     pub value: TokenValue,
@@ -0,0 +1,116 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Runs `cs382cpu check -D memory-bounds` on source given on stdin.
+fn check(source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(["check", "-A", "uninit-read", "-D", "memory-bounds", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn pool_slot_holding_stack_top() {
+    let output = check(
+        ".text
+    LD SP, __stack_top
+    LD R2, 1
+    PUSH R2
+    HALT
+.data
+.stack 32
+",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn pool_slot_holding_struct_field() {
+    let output = check(
+        ".struct P
+x: .8b
+y: .8b
+.ends
+.text
+    LD R0, P.y
+    ST R0, [R0, 0]
+    HALT
+",
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("outside the data section"));
+}
+
+#[test]
+fn counted_loop_past_the_end() {
+    let output = check(
+        ".text
+    LD R2, dst
+    LD R3, 0
+loop:
+    ST1 R1, [R2, R3]
+    ADD R3, R3, 1
+    SUB R4, R3, 10
+    CBNZ R4, loop
+    HALT
+.data
+dst: .1b 1, 2, 3
+",
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("0x0040..0x0049, past the end of dst"));
+}
+
+#[test]
+fn counted_loop_within_bounds() {
+    let output = check(
+        ".text
+    LD R2, dst
+    LD R3, 0
+loop:
+    ST1 R1, [R2, R3]
+    ADD R3, R3, 1
+    SUB R4, R3, 3
+    CBNZ R4, loop
+    HALT
+.data
+dst: .1b 1, 2, 3
+",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn loop_until_zero_not_checked() {
+    let output = check(
+        ".text
+    LD R0, src
+    LD R2, dst
+    LD R3, 0
+loop:
+    LD1 R1, [R0, R3]
+    ST1 R1, [R2, R3]
+    ADD R3, R3, 1
+    CBNZ R1, loop
+    HALT
+.data
+src: .string \"hi\"
+dst: .1b 0, 0, 0
+",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+}
@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const SOURCE: &str = ".text
+    ADD R1, R1, 1
+    HALT
+.data
+x: .8b 1
+";
+
+// A ROM whose label holds a '>' and already has contents, and a RAM whose label is the
+// element's text and whose attributes aren't in logisim's order.
+const CIRCUIT: &str = r##"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<project source="3.8.0" version="1.0">
+  <lib desc="#Memory" name="4"/>
+  <circuit name="cpu">
+    <comp lib="4" loc="(570,300)" name="ROM">
+      <a name="addrWidth" val="8"/>
+      <a name="appearance" val="logisim_evolution"/>
+      <a name="contents">addr/data: 8 8
+ff 3*ee
+</a>
+      <a name="label" val="a&gt;b"/>
+    </comp>
+    <comp lib="4" loc="(620,300)" name="RAM">
+      <a name="label">DATA</a>
+      <a name="dataWidth" val="8"/>
+      <a name="addrWidth" val="8"/>
+    </comp>
+  </circuit>
+</project>
+"##;
+
+// Assembles SOURCE into a copy of circuit, in a directory of its own.
+fn load(test: &str, circuit: &str, args: &[&str]) -> (Output, PathBuf) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), SOURCE).unwrap();
+    std::fs::write(dir.join("in.circ"), circuit).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(["--circ", "in.circ", "--circ-out", "out.circ"])
+        .args(args)
+        .arg("p.cry")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    (output, dir)
+}
+
+#[test]
+fn contents_replaced_and_inserted() {
+    let (output, dir) = load(
+        "circ-contents",
+        CIRCUIT,
+        &["--data-memory", "DATA", "--text-memory", "a>b"],
+    );
+    assert!(output.status.success());
+    let expected = CIRCUIT.replace("ff 3*ee\n", "9 2 0 84\n").replace(
+        "      <a name=\"label\">DATA",
+        "      <a name=\"contents\">addr/data: 8 8\n64*0 1\n</a>\n      <a name=\"label\">DATA",
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out.circ")).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn memory_found_by_circuit() {
+    let circuit = CIRCUIT.replace(
+        "  </circuit>\n",
+        "  </circuit>\n  <circuit name=\"rom\">\n    <comp lib=\"4\" loc=\"(0,0)\" name=\"ROM\">\n    </comp>\n  </circuit>\n",
+    );
+    let (output, dir) = load(
+        "circ-circuit",
+        &circuit,
+        &["--data-memory", "DATA", "--text-memory", "rom"],
+    );
+    assert!(output.status.success());
+    let out = std::fs::read_to_string(dir.join("out.circ")).unwrap();
+    assert!(out.contains("ff 3*ee\n"));
+    assert!(out.contains(
+        "name=\"ROM\">\n      <a name=\"contents\">addr/data: 8 8\n9 2 0 84\n</a>\n    </comp>"
+    ));
+}
+
+#[test]
+fn unknown_memory() {
+    let (output, _) = load(
+        "circ-unknown",
+        CIRCUIT,
+        &["--data-memory", "DATA", "--text-memory", "ROM"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("No ROM/RAM labelled \"ROM\" or inside a circuit named \"ROM\""));
+}
+
+#[test]
+fn malformed_circuit() {
+    let circuit = CIRCUIT.replace("</project>", "</circuit>");
+    let (output, dir) = load(
+        "circ-malformed",
+        &circuit,
+        &["--data-memory", "DATA", "--text-memory", "a>b"],
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Couldn't parse circuit"));
+    assert!(!dir.join("out.circ").exists());
+}
@@ -0,0 +1,115 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+const UNFORMATTED: &str = ".text
+loop:   add r1,r1,0x1F // count
+  cbz r1 , loop
+        HALT   // stop
+  // done
+
+
+.data
+msg: .asciz \"hi\\n\"
+n: .8b 10 // ten
+";
+
+const FORMATTED: &str = ".text
+loop:   ADD    R1, R1, 0x1f // count
+        CBZ    R1, loop
+        HALT // stop
+        // done
+
+.data
+msg:    .asciz \"hi\\n\"
+n:      .8b    10 // ten
+";
+
+// Runs `cs382cpu fmt -` on source given on stdin.
+fn fmt(source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(["fmt", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Runs `cs382cpu fmt` with args on p.cry holding source, in a directory of its own.
+fn fmt_file(test: &str, source: &str, args: &[&str]) -> (Output, PathBuf) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .arg("fmt")
+        .args(args)
+        .arg("p.cry")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    (output, dir.join("p.cry"))
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn formats() {
+    let output = fmt(UNFORMATTED);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), FORMATTED);
+}
+
+#[test]
+fn idempotent() {
+    let output = fmt(FORMATTED);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), FORMATTED);
+}
+
+#[test]
+fn check() {
+    let (output, file) = fmt_file("fmt-check-unformatted", UNFORMATTED, &["--check"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("\"p.cry\" isn't formatted, from line 2"));
+    assert_eq!(std::fs::read_to_string(file).unwrap(), UNFORMATTED);
+
+    let (output, _) = fmt_file("fmt-check-formatted", FORMATTED, &["--check"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn in_place() {
+    let (output, file) = fmt_file("fmt-in-place", UNFORMATTED, &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(std::fs::read_to_string(file).unwrap(), FORMATTED);
+}
+
+#[test]
+fn meaning_kept() {
+    // upper-casing the register lr would turn it into the label LR
+    let source = ".text
+    ADD lr, lr, 1
+    HALT
+.data
+LR: .8b 1
+";
+    let (output, file) = fmt_file("fmt-meaning", source, &[]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Formatting \"p.cry\" would change what it means"));
+    assert_eq!(std::fs::read_to_string(file).unwrap(), source);
+}
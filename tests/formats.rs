@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const SOURCE: &str = ".text
+    ADD R1, R1, 1
+    HALT
+.data
+x: .8b 1
+";
+
+// Assembles SOURCE as p.cry in a directory of its own, where the images are written.
+fn assemble(test: &str, args: &[&str]) -> (Output, PathBuf) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), SOURCE).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(args)
+        .arg("p.cry")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    (output, dir)
+}
+
+fn read(dir: &Path, file: &str) -> String {
+    std::fs::read_to_string(dir.join(file)).unwrap()
+}
+
+#[test]
+fn binary() {
+    let (output, dir) = assemble("formats-binary", &["--format", "bin"]);
+    assert!(output.status.success());
+    let data = std::fs::read(dir.join("p_data_section.bin")).unwrap();
+    assert_eq!(data.len(), 128);
+    assert_eq!(data[0x40], 1);
+    let text = std::fs::read(dir.join("p_text_section.bin")).unwrap();
+    assert_eq!(text[..4], [0x09, 0x02, 0x00, 0x84]);
+}
+
+#[test]
+fn intel_hex() {
+    let (output, dir) = assemble("formats-ihex", &["--format", "ihex"]);
+    assert!(output.status.success());
+    let text = read(&dir, "p_text_section.hex");
+    assert_eq!(
+        text.lines().next(),
+        Some(":100000000902008400000000000000000000000061")
+    );
+    assert_eq!(text.lines().last(), Some(":00000001FF"));
+}
+
+#[test]
+fn s_record() {
+    let (output, dir) = assemble("formats-srec", &["--format", "srec"]);
+    assert!(output.status.success());
+    let text = read(&dir, "p_text_section.srec");
+    let lines: Vec<&str> = text.lines().collect();
+    // the S0 header holds the image name
+    assert_eq!(lines[0], "S0110000705F746578745F73656374696F6E06");
+    assert_eq!(lines[1], "S1130000090200840000000000000000000000005D");
+    assert_eq!(lines.last(), Some(&"S9030000FC"));
+}
+
+#[test]
+fn readmem() {
+    let (output, dir) = assemble(
+        "formats-readmem",
+        &["--format", "data=readmemb,text=readmemh"],
+    );
+    assert!(output.status.success());
+    assert!(read(&dir, "p_text_section.memh").starts_with("@0000 09 02 00 84 00"));
+    assert!(read(&dir, "p_data_section.memb")
+        .lines()
+        .nth(4)
+        .unwrap()
+        .starts_with("@0040 00000001 00000000"));
+}
+
+#[test]
+fn arrays() {
+    let (output, dir) = assemble("formats-arrays", &["--format", "data=c,text=rust"]);
+    assert!(output.status.success());
+    let c = read(&dir, "p_data_section.h");
+    assert!(c.starts_with("#ifndef P_DATA_SECTION_H\n#define P_DATA_SECTION_H\n"));
+    let rust = read(&dir, "p_text_section.rs");
+    assert!(
+        rust.starts_with("pub const P_TEXT_SECTION: [u8; 128] = [\n    0x09, 0x02, 0x00, 0x84,")
+    );
+}
+
+#[test]
+fn unknown_format() {
+    let (output, _) = assemble("formats-unknown", &["--format", "nope"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("\"nope\""));
+}
@@ -0,0 +1,122 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Runs `cs382cpu check` with args on source given on stdin.
+fn check(args: &[&str], source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .arg("check")
+        .args(args)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn shift_range() {
+    let output = check(
+        &["-A", "uninit-read"],
+        ".text
+    LSL R1, R1, 63
+    LSL R1, R1, 64
+    ASR R1, R1, 0xFFFF
+    HALT
+",
+    );
+    assert!(output.status.success());
+    let stderr = stderr(&output);
+    assert!(!stderr.contains("at 2:5"));
+    assert!(stderr.contains("LSL by 64, more than the 63 bits a register can shift at 3:5"));
+    assert!(stderr.contains("ASR by 65535, more than the 63 bits a register can shift at 4:5"));
+}
+
+#[test]
+fn uninit_read_at_each_instruction() {
+    let output = check(
+        &[],
+        ".text
+    ADD R2, R1, R1
+    CBZ R2, other
+    HALT
+other:
+    ADD R3, R1, 1
+    HALT
+",
+    );
+    let stderr = stderr(&output);
+    assert_eq!(
+        stderr
+            .matches("R1 is read before anything writes it")
+            .count(),
+        2
+    );
+    assert!(stderr.contains("at 2:5 [uninit-read]"));
+    assert!(stderr.contains("at 6:5 [uninit-read]"));
+}
+
+#[test]
+fn uninit_read_through_a_call() {
+    let source = |callee: &str| {
+        format!(
+            ".text
+    CALL init
+    ADD R2, R1, 1
+    HALT
+init:
+{}    RET
+",
+            callee
+        )
+    };
+    let output = check(
+        &["--isa-ext", "-D", "uninit-read"],
+        &source("    ADD R1, RZR, 5\n"),
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = check(&["--isa-ext", "-D", "uninit-read"], &source(""));
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("R1 is read before anything writes it at 3:5"));
+}
+
+#[test]
+fn infinite_loop_at_its_header() {
+    let output = check(
+        &["-A", "uninit-read"],
+        ".text
+    LD R0, 3
+spin:
+    ADD R1, R1, 1
+    B spin
+",
+    );
+    assert!(stderr(&output).contains("Loop with no way to reach a HALT at 4:5 [infinite-loop]"));
+
+    // a loop that leads on to one that never stops isn't one itself
+    let output = check(
+        &["-A", "uninit-read"],
+        ".text
+spin:
+    CBZ R0, out
+    B spin
+out:
+    ADD R1, R1, 1
+    B out
+",
+    );
+    let stderr = stderr(&output);
+    assert_eq!(stderr.matches("[infinite-loop]").count(), 1);
+    assert!(stderr.contains("at 6:5 [infinite-loop]"));
+}
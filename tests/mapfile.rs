@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// Assembles source with `--emit map` in a directory of its own and returns the map file.
+fn map(test: &str, source: &str) -> String {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(["asm", "--emit", "map", "p.cry"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    std::fs::read_to_string(dir.join("p.map")).unwrap()
+}
+
+#[test]
+fn register_aliases() {
+    let map = map(
+        "mapfile-aliases",
+        ".text
+.req cnt, r1
+    ADD cnt, cnt, 1
+.unreq cnt
+    HALT
+",
+    );
+    assert!(map.contains(
+        "\nRegister aliases\nNAME                 REGISTER DEFINED AT RANGES\n\
+         cnt                  R1       2:6        0x0080-0x0084\n"
+    ));
+}
+
+#[test]
+fn no_register_aliases() {
+    let map = map(
+        "mapfile-no-aliases",
+        ".text
+    ADD lr, lr, 1
+    HALT
+",
+    );
+    assert!(!map.contains("Register aliases"));
+    assert!(map.contains("USED AT\n\nSections\n"));
+}
@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn cs382cpu(dir: &PathBuf, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+// Assembles source with -O in a directory of its own. Returns the rewrites logged and the
+// disassembled instructions.
+fn optimize(test: &str, source: &str) -> (String, Vec<String>) {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), source).unwrap();
+    let output = cs382cpu(&dir, &["asm", "p.cry", "-O", "-v"]);
+    let log = String::from_utf8_lossy(&output.stderr).to_string();
+    let output = cs382cpu(&dir, &["disasm", "p_text_section.txt"]);
+    let instructions = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with("    "))
+        .map(|line| line.split("//").next().unwrap().trim().to_string())
+        .collect();
+    (log, instructions)
+}
+
+#[test]
+fn remove_noops() {
+    let (log, instructions) = optimize(
+        "peephole-noops",
+        ".text
+    ADD R1, R1, 0
+    MUL R2, R2, 1
+    ADD RZR, R1, 3
+    DIV RZR, R1, 0
+    ADD R3, R1, 1
+    HALT
+",
+    );
+    assert!(log.contains("-O: removed ADD R1, R1, 0 at 2:5, which does nothing"));
+    assert!(log.contains("-O: removed MUL R2, R2, 1 at 3:5, which does nothing"));
+    assert!(log.contains("at 4:5, which does nothing"));
+    // a division by zero still stops the CPU
+    assert_eq!(instructions, ["DIV RZR, R1, 0", "ADD R3, R1, 1", "HALT"]);
+}
+
+#[test]
+fn fold_adds() {
+    let (log, instructions) = optimize(
+        "peephole-fold",
+        ".text
+    ADD R2, R1, 2
+    SUB R2, R2, 5
+    ADD R3, R1, 2
+here:
+    ADD R3, R3, 5
+    CBZ R3, here
+    HALT
+",
+    );
+    assert!(log
+        .contains("-O: folded ADD R2, R1, 2 at 2:5 and SUB R2, R2, 5 at 3:5 into one instruction"));
+    // nothing is folded across a label
+    assert_eq!(
+        instructions,
+        [
+            "SUB R2, R1, 3",
+            "ADD R3, R1, 2",
+            "ADD R3, R3, 5",
+            "CBZ R3, L0088",
+            "HALT",
+        ]
+    );
+}
+
+#[test]
+fn thread_branches() {
+    let (log, instructions) = optimize(
+        "peephole-thread",
+        ".text
+    CBZ R1, jump
+    ADD R2, R2, 1
+    HALT
+jump:
+    B done
+    ADD R2, R2, 2
+done:
+    ADD R2, R2, 3
+    HALT
+",
+    );
+    assert!(log.contains("-O: threaded CBZ R1, jump at 2:5 through B done at 6:5"));
+    assert!(log.contains("-O: removed B done at 6:5, which branches to the next instruction"));
+    assert!(log.contains("-O: removed ADD R2, R2, 2 at 7:5, which can't be reached"));
+    assert_eq!(
+        instructions,
+        [
+            "CBZ R1, L008c",
+            "ADD R2, R2, 1",
+            "HALT",
+            "ADD R2, R2, 3",
+            "HALT"
+        ]
+    );
+}
+
+#[test]
+fn branch_cycle_left_alone() {
+    let (log, instructions) = optimize(
+        "peephole-cycle",
+        ".text
+    CBZ R1, ping
+    CBZ R2, stop
+    HALT
+ping:
+    B pong
+stop:
+    HALT
+pong:
+    B ping
+",
+    );
+    assert!(!log.contains("threaded"));
+    assert_eq!(
+        instructions,
+        [
+            "CBZ R1, L008c",
+            "CBZ R2, L0090",
+            "HALT",
+            "B L0094",
+            "HALT",
+            "B L008c",
+            "HALT",
+        ]
+    );
+}
+
+#[test]
+fn immediates_at_the_limits() {
+    // 0xFFFF is kept as the bits of the field, which read as -1, and a sum past what the field
+    // holds isn't folded
+    let (log, instructions) = optimize(
+        "peephole-immediate",
+        ".text
+    AND R1, R2, 0xFFFF
+    SUB R3, R1, 0x7FFF
+    SUB R3, R3, 1
+    ADD R3, R3, 0
+    HALT
+",
+    );
+    assert!(!log.contains("folded"));
+    assert_eq!(
+        instructions,
+        [
+            "AND R1, R2, -1",
+            "SUB R3, R1, 32767",
+            "SUB R3, R3, 1",
+            "HALT"
+        ]
+    );
+}
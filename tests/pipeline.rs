@@ -0,0 +1,133 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const LOOP: &str = ".text
+    LD R0, 3
+    LD R1, 2
+loop:
+    ADD R4, R4, R1
+    SUB R0, R0, 1
+    CBNZ R0, loop
+    HALT
+";
+
+fn cs382cpu(dir: &PathBuf, args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+// Assembles source for a 5-stage pipeline without forwarding in a directory of its own,
+// handling hazards the given way, and returns the disassembled instructions.
+fn schedule(test: &str, source: &str, hazards: &str) -> Vec<String> {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), source).unwrap();
+    cs382cpu(
+        &dir,
+        &["asm", "p.cry", "--pipeline", "5", "--hazards", hazards],
+    );
+    let output = cs382cpu(&dir, &["disasm", "p_text_section.txt"]);
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.starts_with("    "))
+        .map(|line| line.split("//").next().unwrap().trim().to_string())
+        .collect()
+}
+
+// Checks source for the hazards of the same pipeline and returns the warnings.
+fn hazards(test: &str, source: &str) -> String {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), source).unwrap();
+    let output = cs382cpu(
+        &dir,
+        &["check", "p.cry", "--pipeline", "5", "-A", "uninit-read"],
+    );
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn report() {
+    let warnings = hazards("pipeline-report", LOOP);
+    assert!(warnings
+        .contains("Load-use hazard on R1: ADD at 5:5 reads what LD at 3:5 writes, 2 stall cycles"));
+    assert!(warnings
+        .contains("RAW hazard on R0: CBNZ at 7:5 reads what SUB at 6:5 writes, 2 stall cycles"));
+}
+
+#[test]
+fn nop_insertion() {
+    let nop = "ADD RZR, RZR, 0";
+    assert_eq!(
+        schedule("pipeline-nop", LOOP, "nop"),
+        [
+            "LD R0, 0x0000",
+            "LD R1, 0x0008",
+            nop,
+            nop,
+            "ADD R4, R4, R1",
+            "SUB R0, R0, 1",
+            nop,
+            nop,
+            "CBNZ R0, L0090",
+            "HALT",
+        ]
+    );
+    // the same program written out leaves nothing to stall on
+    let scheduled = ".text
+    LD R0, 3
+    LD R1, 2
+    NOP
+    NOP
+loop:
+    ADD R4, R4, R1
+    SUB R0, R0, 1
+    NOP
+    NOP
+    CBNZ R0, loop
+    HALT
+";
+    assert_eq!(hazards("pipeline-nop-check", scheduled), "");
+}
+
+#[test]
+fn reorder_against_terminator_and_previous_block() {
+    // SUB goes first so its result is ready for CBNZ sooner, and the LD ahead of the loop still
+    // needs a NOP before it
+    assert_eq!(
+        schedule("pipeline-reorder", LOOP, "reorder"),
+        [
+            "LD R0, 0x0000",
+            "LD R1, 0x0008",
+            "ADD RZR, RZR, 0",
+            "SUB R0, R0, 1",
+            "ADD R4, R4, R1",
+            "ADD RZR, RZR, 0",
+            "CBNZ R0, L008c",
+            "HALT",
+        ]
+    );
+    let scheduled = ".text
+    LD R0, 3
+    LD R1, 2
+    NOP
+loop:
+    SUB R0, R0, 1
+    ADD R4, R4, R1
+    NOP
+    CBNZ R0, loop
+    HALT
+";
+    assert_eq!(hazards("pipeline-reorder-check", scheduled), "");
+}
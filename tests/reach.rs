@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// A small layout so offsets run out quickly with a 6-bit imm field
+const SMALL: [&str; 6] = [
+    "--constant-pool",
+    "0x10,0x10",
+    "--text",
+    "0x20,0xa0",
+    "--data",
+    "0xc0,0x40",
+];
+
+// Assembles source in a directory of its own, with isa.toml's imm field narrowed to width bits.
+fn assemble(test: &str, width: u32, source: &str, args: &[&str]) -> Output {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("p.cry"), source).unwrap();
+    let isa = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/isa.toml")).unwrap();
+    let imm = "imm = { lsb = 9, width = 16 }";
+    assert!(isa.contains(imm));
+    let isa = isa.replace(imm, &format!("imm = {{ lsb = 9, width = {} }}", width));
+    std::fs::write(dir.join("isa.toml"), isa).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(["asm", "p.cry", "--isa", "isa.toml"])
+        .args(args)
+        .current_dir(&dir)
+        .output()
+        .unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn pool_entry_out_of_reach() {
+    let output = assemble(
+        "reach-pool",
+        16,
+        ".text
+    LD R0, 5
+    HALT
+",
+        &[
+            "--constant-pool",
+            "0,64",
+            "--data",
+            "64,64",
+            "--text",
+            "0x10000,128",
+        ],
+    );
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(
+        "LD at 2:5 can't reach the constant pool entry for 5 at 0x0000, -65536 bytes away \
+         (it reaches -32768..32767)"
+    ));
+}
+
+#[test]
+fn branch_out_of_reach() {
+    let source = |adds: usize| {
+        format!(
+            ".text
+loop:
+{}    CBZ R1, loop
+    HALT
+",
+            "    ADD R1, R1, 1\n".repeat(adds)
+        )
+    };
+    let output = assemble("reach-branch-within", 6, &source(8), &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = assemble("reach-branch", 6, &source(9), &[]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(
+        "CBZ at 12:5 can't reach \"loop\" at 0x0080, -36 bytes away (it reaches -32..31)"
+    ));
+}
+
+#[test]
+fn branch_out_of_reach_after_rewrite() {
+    let source = ".text
+    LD R0, 3
+loop:
+    ADD R1, R1, 1
+    ADD R2, R1, 1
+    ADD R3, R2, 1
+    ADD R4, R3, 1
+    SUB R0, R0, 1
+    CBNZ R0, loop
+    HALT
+";
+    let output = assemble("reach-rewrite-branch-before", 6, source, &SMALL);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let mut args = SMALL.to_vec();
+    args.extend(["--pipeline", "7", "--hazards", "nop"]);
+    let output = assemble("reach-rewrite-branch", 6, source, &args);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(
+        "Rewriting the text section puts the branch target of CBNZ at 9:5 out of reach, \
+         -84 bytes away (it reaches -32..31)"
+    ));
+}
+
+#[test]
+fn pool_entry_out_of_reach_after_rewrite() {
+    let source = ".text
+    ADD R1, R1, 1
+    ADD R2, R1, 1
+    ADD R3, R2, 1
+    LD R0, 3
+    HALT
+";
+    let output = assemble("reach-rewrite-pool-before", 6, source, &SMALL);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let mut args = SMALL.to_vec();
+    args.extend(["--pipeline", "7", "--hazards", "nop"]);
+    let output = assemble("reach-rewrite-pool", 6, source, &args);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(
+        "Rewriting the text section puts the constant pool entry of LD at 5:5 out of reach, \
+         -60 bytes away (it reaches -32..31)"
+    ));
+}
@@ -0,0 +1,83 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Runs `cs382cpu run` on source given on stdin.
+fn run(source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(["run", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn alias_is_a_register() {
+    let output = run(".text
+    ADD lr, lr, 3
+    HALT
+");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("R6   = 0x0000000000000003"));
+}
+
+#[test]
+fn label_named_like_an_alias() {
+    // lr was a plain label before BL/BR took it as the link register's name
+    let output = run(".text
+    LD R1, lr
+    ADD R6, R6, 3
+    HALT
+.data
+lr: .char 'A'
+");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("R1   = 0x0000000000000040"));
+    assert!(stdout.contains("R6   = 0x0000000000000003"));
+}
+
+#[test]
+fn label_named_sp() {
+    // sp is the label here, but PUSH and POP still use the stack pointer
+    let output = run(".text
+    LD R5, __stack_top
+    LD R1, sp
+    PUSH R1
+    POP R2
+    HALT
+.data
+sp: .char 'A'
+.stack 16
+");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("R2   = 0x0000000000000040"));
+}
+
+#[test]
+fn label_named_like_a_register() {
+    let output = run(".text
+    LD R1, r5
+    HALT
+.data
+r5: .char 'A'
+");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("\"r5\" is a register name and can't be a label at 5:1"));
+}
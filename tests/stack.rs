@@ -0,0 +1,57 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Runs `cs382cpu run` on source given on stdin.
+fn run(source: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cs382cpu"))
+        .args(["run", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+#[test]
+fn stack_top_before_stack() {
+    let output = run(".text
+    LD SP, __stack_top
+    ADD R2, R2, 7
+    PUSH R2
+    POP R3
+    HALT
+.data
+.stack 32
+");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("R3   = 0x0000000000000007"));
+    assert!(stdout.contains("R5   = 0x0000000000000080"));
+}
+
+#[test]
+fn stack_reserved_twice() {
+    let output = run(".text
+    HALT
+.data
+.stack 16
+.stack 16
+");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("The stack is already reserved"));
+}